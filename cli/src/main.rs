@@ -0,0 +1,264 @@
+//! `tantivy-server-cli`, an admin client speaking the TCP protocol.
+//!
+//! Each subcommand makes one call and prints the reply as JSON. Without a
+//! subcommand, or with `repl`, it reads the same subcommands from a prompt.
+
+mod repl;
+
+use clap::{Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use tantivy_server_client::protocol::{
+    AddResult, ByteOrder, Cmd, DocValue, Encoding, IndexQuery, IndexSchema, Payload, QueryItem,
+    UploadBegin, UploadChunk, UploadEnd,
+};
+use tantivy_server_client::{Client, ClientConfig};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser, Debug)]
+#[clap(name = "tantivy-server-cli", version, about)]
+struct Cli {
+    /// `host:port` of the server's TCP listener.
+    #[clap(short, long, default_value = "127.0.0.1:8099")]
+    addr: String,
+    /// Byte order of the frame length prefixes: little or big.
+    #[clap(long, default_value = "little", value_parser = parse_enum::<ByteOrder>)]
+    byteorder: ByteOrder,
+    /// Set when the server has `handshake_on_connect`.
+    #[clap(long)]
+    handshake_on_connect: bool,
+    /// Frame encoding: json, msgpack or cbor.
+    #[clap(long, default_value = "json", value_parser = parse_enum::<Encoding>)]
+    encoding: Encoding,
+    /// API key.
+    #[clap(long)]
+    token: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Top>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Top {
+    #[clap(flatten)]
+    Admin(Command),
+    /// Read commands from a prompt (the default).
+    Repl,
+}
+
+/// The commands available both on the command line and in the REPL.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create an index from a JSON schema.
+    Create {
+        /// An `IndexSchema`: `{"index": ..., "field": [...]}`.
+        #[clap(long, value_parser)]
+        schema: PathBuf,
+    },
+    /// Add the documents of a newline-delimited JSON file, committed once
+    /// at the end.
+    Add {
+        #[clap(short, long)]
+        index: String,
+        #[clap(long, value_parser)]
+        ndjson: PathBuf,
+        /// Documents per upload chunk.
+        #[clap(long, default_value = "1000")]
+        batch: usize,
+    },
+    /// Search an index.
+    Search {
+        #[clap(short, long)]
+        index: String,
+        /// Query in tantivy's query syntax.
+        query: String,
+        #[clap(short, long, default_value = "10")]
+        size: usize,
+        #[clap(short, long, default_value = "0")]
+        offset: usize,
+        /// Return stored values rather than snippets.
+        #[clap(long)]
+        no_highlight: bool,
+    },
+    /// Delete the documents with a term, or all of them.
+    Delete {
+        #[clap(short, long)]
+        index: String,
+        #[clap(short, long, requires = "text", required_unless_present = "all")]
+        field: Option<String>,
+        #[clap(short, long, requires = "field")]
+        text: Option<String>,
+        #[clap(long, conflicts_with = "field")]
+        all: bool,
+    },
+    /// Commit the adds and deletes the index's commit policy holds back.
+    Commit {
+        #[clap(short, long)]
+        index: String,
+    },
+    /// Merge an index's segments, then remove the files no longer used.
+    Merge {
+        #[clap(short, long)]
+        index: String,
+        /// How many segments may be left.
+        #[clap(long, default_value = "1")]
+        max_segments: usize,
+    },
+    /// Show an index's schema and segments.
+    Describe {
+        #[clap(short, long)]
+        index: String,
+    },
+    /// Show server uptime and per-index document counts.
+    Stats {
+        /// Only this index.
+        #[clap(short, long)]
+        index: Option<String>,
+    },
+}
+
+/// Parses the lowercase names the protocol uses for its enums.
+fn parse_enum<T: DeserializeOwned>(s: &str) -> std::result::Result<T, String> {
+    serde_json::from_value(Value::from(s.to_lowercase())).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let mut config = ClientConfig::new(cli.addr);
+    config.byteorder = cli.byteorder;
+    config.handshake_on_connect = cli.handshake_on_connect;
+    config.encoding = cli.encoding;
+    config.token = cli.token;
+    let command = cli.command;
+    let result = Client::connect(config)
+        .map_err(Into::into)
+        .and_then(|mut client| match command {
+            Some(Top::Admin(command)) => run(&mut client, command),
+            Some(Top::Repl) | None => repl::run(&mut client),
+        });
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Makes the call for `command` and prints the reply.
+pub fn run(client: &mut Client, command: Command) -> Result<()> {
+    let reply = match command {
+        Command::Create { schema } => {
+            let schema: IndexSchema = serde_json::from_slice(&fs::read(&schema)?)?;
+            client.create(&schema)?;
+            serde_json::json!({ "created": schema.index })
+        }
+        Command::Add {
+            index,
+            ndjson,
+            batch,
+        } => serde_json::to_value(upload(client, index, &ndjson, batch.max(1))?)?,
+        Command::Search {
+            index,
+            query,
+            size,
+            offset,
+            no_highlight,
+        } => serde_json::to_value(client.search(&IndexQuery {
+            index,
+            param: query,
+            size,
+            offset,
+            highlight: !no_highlight,
+        })?)?,
+        Command::Delete {
+            index,
+            field,
+            text,
+            all: _,
+        } => serde_json::to_value(client.delete(&QueryItem {
+            index,
+            // Empty when `--all` is given, which deletes every document.
+            field: field.unwrap_or_default(),
+            text: text.unwrap_or_default(),
+        })?)?,
+        Command::Commit { index } => json!({ "opstamp": client.commit(&index)? }),
+        Command::Merge {
+            index,
+            max_segments,
+        } => serde_json::to_value(client.merge(&index, max_segments)?)?,
+        Command::Describe { index } => serde_json::to_value(client.describe(&index)?)?,
+        Command::Stats { index } => serde_json::to_value(client.stats(index.as_deref())?)?,
+    };
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
+
+/// Streams `path` to `index` in chunks of `batch` documents.
+fn upload(client: &mut Client, index: String, path: &Path, batch: usize) -> Result<AddResult> {
+    let reader = BufReader::new(File::open(path)?);
+    client.call(Cmd::UploadBegin, &UploadBegin { index })?;
+    let result = upload_chunks(client, reader, path, batch);
+    if result.is_err() {
+        // Rather than an UploadEnd, which would commit what was sent, the
+        // server rolls the upload back once its connection is gone.
+        client.disconnect();
+    }
+    result
+}
+
+/// Sends the documents of an upload begun on `client`, then ends it.
+fn upload_chunks(
+    client: &mut Client,
+    reader: BufReader<File>,
+    path: &Path,
+    batch: usize,
+) -> Result<AddResult> {
+    let mut data: Vec<HashMap<String, DocValue>> = Vec::with_capacity(batch);
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let doc = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", path.display(), n + 1, e))?;
+        data.push(doc);
+        if data.len() == batch {
+            client.call(Cmd::UploadChunk, &UploadChunk { data })?;
+            data = Vec::with_capacity(batch);
+        }
+    }
+    if !data.is_empty() {
+        client.call(Cmd::UploadChunk, &UploadChunk { data })?;
+    }
+    match client.call(Cmd::UploadEnd, &UploadEnd {})? {
+        Payload::Added(result) => Ok(result),
+        other => Err(format!("unexpected payload {:?}", other).into()),
+    }
+}
+
+#[test]
+fn test_parse_args() {
+    let cli = Cli::parse_from([
+        "tantivy-server-cli",
+        "--byteorder",
+        "Big",
+        "delete",
+        "-i",
+        "book",
+        "--all",
+    ]);
+    assert_eq!(cli.byteorder, ByteOrder::Big);
+    match cli.command {
+        Some(Top::Admin(Command::Delete { field, all, .. })) => {
+            assert_eq!(field, None);
+            assert!(all);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(Cli::try_parse_from(["tantivy-server-cli", "delete", "-i", "book"]).is_err());
+    assert!(Cli::try_parse_from(["tantivy-server-cli", "--encoding", "xml", "stats"]).is_err());
+}
@@ -0,0 +1,130 @@
+use crate::{Command, Result};
+use clap::{ErrorKind, Parser};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::env;
+use std::path::PathBuf;
+use tantivy_server_client::Client;
+
+const PROMPT: &str = "tantivy> ";
+
+/// One line typed at the prompt.
+#[derive(Parser, Debug)]
+#[clap(name = "", no_binary_name = true, disable_version_flag = true)]
+struct Line {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Reads commands until `exit` or end of input. A failed command is
+/// reported and the prompt comes back.
+pub fn run(client: &mut Client) -> Result<()> {
+    let mut editor = Editor::<()>::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history on the first run.
+        let _ = editor.load_history(path);
+    }
+    if let Some(handshake) = client.handshake() {
+        println!(
+            "connected to {} {}, type help for the commands",
+            handshake.greeting, handshake.version
+        );
+    }
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line);
+        if line == "exit" || line == "quit" {
+            break;
+        }
+        let words = match split_line(line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                continue;
+            }
+        };
+        match Line::try_parse_from(words) {
+            Ok(parsed) => {
+                if let Err(e) = crate::run(client, parsed.command) {
+                    eprintln!("error: {}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::DisplayHelp => {
+                let _ = e.print();
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".tantivy_server_history"))
+}
+
+/// Splits a line into words like a shell does: on whitespace, keeping
+/// quoted text together, with `\` escaping the next character outside
+/// single quotes.
+fn split_line(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let escaped = chars.next().ok_or("trailing \\")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("unterminated {}", q));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[test]
+fn test_split_line() {
+    assert_eq!(
+        split_line(r#"search -i book "title:\"of mice\" AND year:1937"  "#).unwrap(),
+        vec!["search", "-i", "book", r#"title:"of mice" AND year:1937"#]
+    );
+    assert_eq!(
+        split_line(r"delete -i book -f title -t 'it\s' ''").unwrap(),
+        vec!["delete", "-i", "book", "-f", "title", "-t", r"it\s", ""]
+    );
+    assert!(split_line("search -i book \"unterminated").is_err());
+
+    let words = split_line("search -i book mice --size 5").unwrap();
+    match Line::try_parse_from(words).unwrap().command {
+        Command::Search { query, size, .. } => {
+            assert_eq!(query, "mice");
+            assert_eq!(size, 5);
+        }
+        other => panic!("{:?}", other),
+    }
+}
@@ -0,0 +1,283 @@
+use crate::call::{self, HELLO_ID};
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use crate::pool::Poolable;
+use log::warn;
+use serde::Serialize;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+use tantivy_server_protocol::{
+    AddResult, Cmd, DeleteResult, Framing, Handshake, IndexData, IndexInfo, IndexName, IndexQuery,
+    IndexSchema, MergeQuery, MergeResult, Payload, QueryItem, Response, SearchResult, ServerStats,
+    StatsQuery,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// The tokio counterpart of `Client`.
+pub struct AsyncClient {
+    config: ClientConfig,
+    conn: Option<Conn>,
+    next_id: u64,
+}
+
+struct Conn {
+    stream: BufStream<TcpStream>,
+    framing: Framing,
+    handshake: Handshake,
+}
+
+impl AsyncClient {
+    /// Connects and negotiates, failing if the server cannot be reached.
+    pub async fn connect(config: ClientConfig) -> Result<AsyncClient> {
+        let conn = Conn::open(&config).await?;
+        Ok(AsyncClient {
+            config,
+            conn: Some(conn),
+            next_id: HELLO_ID,
+        })
+    }
+
+    /// The server's answer to the last `Hello`, while connected.
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.conn.as_ref().map(|conn| &conn.handshake)
+    }
+
+    /// Closes the connection, so that the server drops what it left
+    /// unfinished, like a chunked upload. The next call connects again.
+    pub fn disconnect(&mut self) {
+        self.conn = None;
+    }
+
+    pub async fn create(&mut self, schema: &IndexSchema) -> Result<()> {
+        match self.call(Cmd::Create, schema).await? {
+            Payload::Created {} => Ok(()),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub async fn add(&mut self, data: &IndexData) -> Result<AddResult> {
+        match self.call(Cmd::Add, data).await? {
+            Payload::Added(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub async fn search(&mut self, query: &IndexQuery) -> Result<SearchResult> {
+        match self.call(Cmd::Search, query).await? {
+            Payload::Search(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub async fn delete(&mut self, item: &QueryItem) -> Result<DeleteResult> {
+        match self.call(Cmd::Delete, item).await? {
+            Payload::Deleted(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub async fn describe(&mut self, index: &str) -> Result<IndexInfo> {
+        let name = IndexName {
+            index: index.to_string(),
+        };
+        match self.call(Cmd::Describe, &name).await? {
+            Payload::Describe(info) => Ok(info),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Commits what the index's commit policy holds back, returning the
+    /// commit's opstamp.
+    pub async fn commit(&mut self, index: &str) -> Result<u64> {
+        let name = IndexName {
+            index: index.to_string(),
+        };
+        match self.call(Cmd::Commit, &name).await? {
+            Payload::Committed { opstamp } => Ok(opstamp),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Merges the index down to at most `max_segments` segments.
+    pub async fn merge(&mut self, index: &str, max_segments: usize) -> Result<MergeResult> {
+        let query = MergeQuery {
+            index: index.to_string(),
+            max_segments,
+        };
+        match self.call(Cmd::Merge, &query).await? {
+            Payload::Merged(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Server stats, for `index` alone or every index the key may read.
+    pub async fn stats(&mut self, index: Option<&str>) -> Result<ServerStats> {
+        let query = StatsQuery {
+            index: index.map(str::to_string),
+        };
+        match self.call(Cmd::Stats, &query).await? {
+            Payload::Stats(stats) => Ok(stats),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Sends any command and waits for its reply, reconnecting first if the
+    /// connection broke.
+    pub async fn call<B: Serialize>(&mut self, cmd: Cmd, body: &B) -> Result<Payload> {
+        let mut attempt = 0;
+        loop {
+            let timeout = self.config.timeout;
+            match within(timeout, self.try_call(cmd, body)).await {
+                Err(e) if e.is_disconnect() => {
+                    self.conn = None;
+                    if !call::is_idempotent(cmd) || attempt >= self.config.retries {
+                        return Err(e);
+                    }
+                    warn!("{:?} to {} failed, retrying: {}", cmd, self.config.addr, e);
+                    time::sleep(self.config.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_call<B: Serialize>(&mut self, cmd: Cmd, body: &B) -> Result<Payload> {
+        if self.conn.is_none() {
+            self.conn = Some(Conn::open(&self.config).await?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        self.next_id += 1;
+        let id = self.next_id;
+        conn.send(&call::request(id, cmd, body)).await?;
+        let response = conn.receive(self.config.max_frame_bytes).await?;
+        call::payload(id, response)
+    }
+}
+
+impl Poolable for AsyncClient {
+    fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+}
+
+impl Conn {
+    async fn open(config: &ClientConfig) -> Result<Conn> {
+        let stream = within(Some(config.connect_timeout), async {
+            Ok(TcpStream::connect(&config.addr).await?)
+        })
+        .await?;
+        stream.set_nodelay(true)?;
+        let mut stream = BufStream::new(stream);
+        within(config.timeout, async move {
+            let max = config.max_frame_bytes;
+            let initial = call::initial_framing(config);
+            if config.handshake_on_connect {
+                let mut prefix = [0u8; 4];
+                stream.read_exact(&mut prefix).await?;
+                let mut greeting = initial;
+                let mut buf = vec![0u8; greeting.first_frame_len(prefix, max)?];
+                stream.read_exact(&mut buf).await?;
+            }
+            write_frame(&mut stream, &initial.encode_first(&call::hello(config))).await?;
+            // The reply to `Hello` already uses what it negotiated.
+            let framing = call::negotiated_framing(config);
+            let response = framing.decode(&read_frame(&mut stream, &framing, max).await?)?;
+            match call::payload(HELLO_ID, response)? {
+                Payload::Hello(handshake) => Ok(Conn {
+                    stream,
+                    framing,
+                    handshake,
+                }),
+                other => Err(call::unexpected(other)),
+            }
+        })
+        .await
+    }
+
+    async fn send<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        let frame = self.framing.encode(msg);
+        write_frame(&mut self.stream, &frame).await
+    }
+
+    async fn receive(&mut self, max: u32) -> Result<Response> {
+        let buf = read_frame(&mut self.stream, &self.framing, max).await?;
+        Ok(self.framing.decode(&buf)?)
+    }
+}
+
+async fn write_frame(stream: &mut BufStream<TcpStream>, frame: &[u8]) -> Result<()> {
+    stream.write_all(frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// `Framing::read` for a tokio stream.
+async fn read_frame(
+    stream: &mut BufStream<TcpStream>,
+    framing: &Framing,
+    max: u32,
+) -> Result<Vec<u8>> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix).await?;
+    let mut buf = vec![0u8; framing.frame_len(prefix, max)?];
+    stream.read_exact(&mut buf).await?;
+    Ok(framing.compression.unpack(buf, max)?)
+}
+
+/// Runs `future`, giving up with a `TimedOut` error after `timeout`.
+async fn within<T, F>(timeout: Option<Duration>, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout {
+        Some(timeout) => match time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Io(io::Error::new(
+                ErrorKind::TimedOut,
+                "request timed out",
+            ))),
+        },
+        None => future.await,
+    }
+}
+
+#[tokio::test]
+async fn test_async_client() {
+    use crate::pool::Pool;
+    use crate::test_server;
+    use tantivy_server_protocol::{Compression, DocValue};
+
+    let server = test_server::start(2);
+    let mut config = ClientConfig::new(server.addr.clone());
+    config.compression = Compression::Zstd;
+    config.compression_conf.threshold_bytes = 0;
+    config.backoff = Duration::from_millis(1);
+    let pool: Pool<AsyncClient> = Pool::new(config, 4);
+    let query = IndexQuery {
+        index: "book".to_string(),
+        param: "title:mice".to_string(),
+        size: 10,
+        offset: 0,
+        highlight: false,
+    };
+    // The server drops each connection after two searches.
+    for &conn in [1, 1, 2].iter() {
+        let mut client = pool.get().await.unwrap();
+        let result = client.search(&query).await.unwrap();
+        assert_eq!(result.hits[0]["conn"], DocValue::U64(conn));
+    }
+    let item = QueryItem {
+        index: "book".to_string(),
+        field: "title".to_string(),
+        text: "mice".to_string(),
+    };
+    match pool.get().await.unwrap().delete(&item).await {
+        Err(Error::Server { code: 200, .. }) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    assert_eq!(pool.idle(), 1);
+}
@@ -0,0 +1,261 @@
+use crate::call::{self, HELLO_ID};
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use crate::pool::Poolable;
+use log::warn;
+use serde::Serialize;
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use tantivy_server_protocol::{
+    AddResult, Cmd, DeleteResult, Framing, Handshake, IndexData, IndexInfo, IndexName, IndexQuery,
+    IndexSchema, MergeQuery, MergeResult, Payload, QueryItem, Response, SearchResult, ServerStats,
+    StatsQuery,
+};
+
+/// A blocking client holding one connection, opened again when it breaks.
+pub struct Client {
+    config: ClientConfig,
+    conn: Option<Conn>,
+    next_id: u64,
+}
+
+struct Conn {
+    stream: BufReader<TcpStream>,
+    framing: Framing,
+    handshake: Handshake,
+}
+
+impl Client {
+    /// Connects and negotiates, failing if the server cannot be reached.
+    pub fn connect(config: ClientConfig) -> Result<Client> {
+        let conn = Conn::open(&config)?;
+        Ok(Client {
+            config,
+            conn: Some(conn),
+            next_id: HELLO_ID,
+        })
+    }
+
+    /// The server's answer to the last `Hello`, while connected.
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.conn.as_ref().map(|conn| &conn.handshake)
+    }
+
+    /// Closes the connection, so that the server drops what it left
+    /// unfinished, like a chunked upload. The next call connects again.
+    pub fn disconnect(&mut self) {
+        self.conn = None;
+    }
+
+    pub fn create(&mut self, schema: &IndexSchema) -> Result<()> {
+        match self.call(Cmd::Create, schema)? {
+            Payload::Created {} => Ok(()),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub fn add(&mut self, data: &IndexData) -> Result<AddResult> {
+        match self.call(Cmd::Add, data)? {
+            Payload::Added(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub fn search(&mut self, query: &IndexQuery) -> Result<SearchResult> {
+        match self.call(Cmd::Search, query)? {
+            Payload::Search(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub fn delete(&mut self, item: &QueryItem) -> Result<DeleteResult> {
+        match self.call(Cmd::Delete, item)? {
+            Payload::Deleted(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    pub fn describe(&mut self, index: &str) -> Result<IndexInfo> {
+        let name = IndexName {
+            index: index.to_string(),
+        };
+        match self.call(Cmd::Describe, &name)? {
+            Payload::Describe(info) => Ok(info),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Commits what the index's commit policy holds back, returning the
+    /// commit's opstamp.
+    pub fn commit(&mut self, index: &str) -> Result<u64> {
+        let name = IndexName {
+            index: index.to_string(),
+        };
+        match self.call(Cmd::Commit, &name)? {
+            Payload::Committed { opstamp } => Ok(opstamp),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Merges the index down to at most `max_segments` segments.
+    pub fn merge(&mut self, index: &str, max_segments: usize) -> Result<MergeResult> {
+        let query = MergeQuery {
+            index: index.to_string(),
+            max_segments,
+        };
+        match self.call(Cmd::Merge, &query)? {
+            Payload::Merged(result) => Ok(result),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Server stats, for `index` alone or every index the key may read.
+    pub fn stats(&mut self, index: Option<&str>) -> Result<ServerStats> {
+        let query = StatsQuery {
+            index: index.map(str::to_string),
+        };
+        match self.call(Cmd::Stats, &query)? {
+            Payload::Stats(stats) => Ok(stats),
+            other => Err(call::unexpected(other)),
+        }
+    }
+
+    /// Sends any command and waits for its reply, reconnecting first if the
+    /// connection broke.
+    pub fn call<B: Serialize>(&mut self, cmd: Cmd, body: &B) -> Result<Payload> {
+        let mut attempt = 0;
+        loop {
+            match self.try_call(cmd, body) {
+                Err(e) if e.is_disconnect() => {
+                    self.conn = None;
+                    if !call::is_idempotent(cmd) || attempt >= self.config.retries {
+                        return Err(e);
+                    }
+                    warn!("{:?} to {} failed, retrying: {}", cmd, self.config.addr, e);
+                    thread::sleep(self.config.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn try_call<B: Serialize>(&mut self, cmd: Cmd, body: &B) -> Result<Payload> {
+        if self.conn.is_none() {
+            self.conn = Some(Conn::open(&self.config)?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        self.next_id += 1;
+        let id = self.next_id;
+        conn.send(&call::request(id, cmd, body))?;
+        let response = conn.receive(self.config.max_frame_bytes)?;
+        call::payload(id, response)
+    }
+}
+
+impl Poolable for Client {
+    fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+}
+
+impl Conn {
+    fn open(config: &ClientConfig) -> Result<Conn> {
+        let stream = connect(config)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(config.timeout)?;
+        stream.set_write_timeout(config.timeout)?;
+        let mut stream = BufReader::new(stream);
+        let initial = call::initial_framing(config);
+        if config.handshake_on_connect {
+            let mut greeting = initial;
+            greeting.read_first(&mut stream, config.max_frame_bytes)?;
+        }
+        stream
+            .get_mut()
+            .write_all(&initial.encode_first(&call::hello(config)))?;
+        // The reply to `Hello` already uses what it negotiated.
+        let framing = call::negotiated_framing(config);
+        let response = framing.read(&mut stream, config.max_frame_bytes)?;
+        let handshake = match call::payload(HELLO_ID, framing.decode(&response)?)? {
+            Payload::Hello(handshake) => handshake,
+            other => return Err(call::unexpected(other)),
+        };
+        Ok(Conn {
+            stream,
+            framing,
+            handshake,
+        })
+    }
+
+    fn send<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(&self.framing.encode(msg))?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn receive(&mut self, max: u32) -> Result<Response> {
+        let buf = self.framing.read(&mut self.stream, max)?;
+        Ok(self.framing.decode(&buf)?)
+    }
+}
+
+fn connect(config: &ClientConfig) -> Result<TcpStream> {
+    let mut last = None;
+    for addr in config.addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, config.connect_timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(Error::Io(last.unwrap_or_else(|| {
+        io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("{} resolves to no address", config.addr),
+        )
+    })))
+}
+
+#[test]
+fn test_client() {
+    use crate::test_server;
+    use std::time::Duration;
+    use tantivy_server_protocol::{Compression, DocValue, Encoding};
+
+    // The server drops each connection after two searches.
+    let server = test_server::start(2);
+    let mut config = ClientConfig::new(server.addr.clone());
+    config.encoding = Encoding::Msgpack;
+    config.compression = Compression::Lz4;
+    config.compression_conf.threshold_bytes = 0;
+    config.backoff = Duration::from_millis(1);
+    let mut client = Client::connect(config).unwrap();
+    assert_eq!(client.handshake().unwrap().encoding, Encoding::Msgpack);
+
+    let query = IndexQuery {
+        index: "book".to_string(),
+        param: "title:mice".to_string(),
+        size: 10,
+        offset: 0,
+        highlight: false,
+    };
+    // The third search finds the connection closed and is retried on a new one.
+    for &conn in [1, 1, 2].iter() {
+        let result = client.search(&query).unwrap();
+        assert_eq!(result.hits[0]["conn"], DocValue::U64(conn));
+        assert_eq!(result.hits[0]["param"], DocValue::from(query.param.clone()));
+    }
+    let data = IndexData {
+        index: "missing".to_string(),
+        data: vec![],
+    };
+    match client.add(&data) {
+        Err(Error::Server { code: 200, .. }) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    // An error reply leaves the connection usable.
+    assert!(client.handshake().is_some());
+    assert_eq!(server.connections(), 2);
+}
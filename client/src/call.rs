@@ -0,0 +1,83 @@
+//! The parts of a call that do not depend on how the socket is driven.
+
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use serde_json::Value;
+use tantivy_server_protocol::{
+    Cmd, Framing, Hello, Payload, Request, Response, Status, PROTOCOL_V2,
+};
+
+/// The id of the `Hello` that opens a connection. Later requests count up
+/// from 1.
+pub const HELLO_ID: u64 = 0;
+
+/// What a connection speaks before the `Hello` is answered.
+pub fn initial_framing(config: &ClientConfig) -> Framing {
+    Framing {
+        compression_conf: config.compression_conf,
+        ..Framing::new(config.byteorder)
+    }
+}
+
+/// What a connection speaks once the `Hello` is answered, the reply
+/// included.
+pub fn negotiated_framing(config: &ClientConfig) -> Framing {
+    Framing {
+        encoding: config.encoding,
+        compression: config.compression,
+        ..initial_framing(config)
+    }
+}
+
+pub fn hello(config: &ClientConfig) -> Request<Hello> {
+    request(
+        HELLO_ID,
+        Cmd::Hello,
+        Hello {
+            protocol: Some(PROTOCOL_V2),
+            encoding: Some(config.encoding),
+            compression: Some(config.compression),
+            token: config.token.clone(),
+            ..Hello::default()
+        },
+    )
+}
+
+pub fn request<B>(id: u64, cmd: Cmd, body: B) -> Request<B> {
+    Request {
+        id: Some(Value::from(id)),
+        cmd,
+        token: None,
+        body,
+    }
+}
+
+/// Unwraps the reply to request `id`.
+pub fn payload(id: u64, response: Response) -> Result<Payload> {
+    if response.id != Some(Value::from(id)) {
+        return Err(Error::Unexpected(format!(
+            "reply to request {:?} while waiting for {}",
+            response.id, id
+        )));
+    }
+    match (response.status, response.payload, response.error) {
+        (Status::Ok, Some(payload), _) => Ok(payload),
+        (Status::Wrong, _, Some(error)) => Err(Error::Server {
+            code: response.code,
+            error,
+        }),
+        _ => Err(Error::Unexpected(format!(
+            "reply to request {} has neither payload nor error",
+            id
+        ))),
+    }
+}
+
+/// Commands that can be sent again without changing the outcome.
+pub fn is_idempotent(cmd: Cmd) -> bool {
+    matches!(cmd, Cmd::Hello | Cmd::Search | Cmd::Describe | Cmd::Stats)
+}
+
+pub fn unexpected(payload: Payload) -> Error {
+    Error::Unexpected(format!("unexpected payload {:?}", payload))
+}
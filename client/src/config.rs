@@ -0,0 +1,54 @@
+use std::time::Duration;
+use tantivy_server_protocol::{ByteOrder, Compression, CompressionConf, Encoding};
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// `host:port` of the server's TCP listener.
+    pub addr: String,
+    /// Byte order of the frame length prefixes. The server tells it from
+    /// the `Hello` that opens a connection, so it need not match the
+    /// server's `byteorder`.
+    pub byteorder: ByteOrder,
+    /// Set when the server has `handshake_on_connect`, so the greeting it
+    /// sends first is skipped.
+    pub handshake_on_connect: bool,
+    pub encoding: Encoding,
+    pub compression: Compression,
+    /// When compression kicks in for requests.
+    pub compression_conf: CompressionConf,
+    /// API key sent in `Hello`.
+    pub token: Option<String>,
+    pub connect_timeout: Duration,
+    /// Limit on each request's round trip; `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Largest reply accepted.
+    pub max_frame_bytes: u32,
+    /// How many times a read-only command is retried on a fresh connection.
+    pub retries: u32,
+    /// Wait before the first reconnect, doubled for each further one.
+    pub backoff: Duration,
+}
+
+impl ClientConfig {
+    pub fn new<A: Into<String>>(addr: A) -> ClientConfig {
+        ClientConfig {
+            addr: addr.into(),
+            byteorder: ByteOrder::Little,
+            handshake_on_connect: false,
+            encoding: Encoding::Json,
+            compression: Compression::None,
+            compression_conf: CompressionConf::default(),
+            token: None,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Some(Duration::from_secs(30)),
+            max_frame_bytes: 256 * 1024 * 1024,
+            retries: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
+
+    /// How long to wait before reconnect `attempt`, counting from 0.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.min(16))
+    }
+}
@@ -0,0 +1,65 @@
+use std::error;
+use std::fmt;
+use std::io;
+use tantivy_server_protocol::{self as protocol, ErrorReply};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A reply that could not be read or decoded.
+    Frame(protocol::Error),
+    /// The server answered with an error; `code` is the server's stable
+    /// error code.
+    Server {
+        code: u16,
+        error: ErrorReply,
+    },
+    /// A reply that does not answer the request sent.
+    Unexpected(String),
+}
+
+impl Error {
+    /// Whether the connection is unusable after this error. Only an error
+    /// reply leaves it in step with the server.
+    pub fn is_disconnect(&self) -> bool {
+        !matches!(self, Error::Server { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Io: {}", e),
+            Error::Frame(e) => write!(f, "Frame: {}", e),
+            Error::Server { error, .. } => write!(f, "{}", error.message),
+            Error::Unexpected(msg) => write!(f, "Unexpected: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Frame(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<protocol::Error> for Error {
+    fn from(e: protocol::Error) -> Error {
+        match e {
+            protocol::Error::Io(e) => Error::Io(e),
+            e => Error::Frame(e),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Clients for the tantivy-server TCP protocol.
+//!
+//! [`Client`] blocks, [`AsyncClient`] runs on tokio (the `async` feature,
+//! on by default). Both open each connection with a `Hello` that switches
+//! it to protocol v2 and the configured encoding and compression, and both
+//! reconnect on the next call once a connection breaks. Read-only commands
+//! are retried on a fresh connection at once; writes are not, since the
+//! server may have applied them before the connection went away.
+//!
+//! [`Pool`] keeps connected clients of either kind for reuse.
+
+#[cfg(feature = "async")]
+mod async_client;
+mod blocking;
+mod call;
+mod config;
+mod error;
+mod pool;
+#[cfg(test)]
+mod test_server;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+pub use blocking::Client;
+pub use config::ClientConfig;
+pub use error::{Error, Result};
+pub use pool::{Pool, Poolable, Pooled};
+pub use tantivy_server_protocol as protocol;
@@ -0,0 +1,129 @@
+use crate::blocking::Client;
+use crate::config::ClientConfig;
+use crate::error::Result;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// A client a `Pool` can hand out again.
+pub trait Poolable {
+    /// False once the client lost its connection; such clients are dropped
+    /// rather than returned to the pool.
+    fn is_connected(&self) -> bool;
+}
+
+/// Connected clients kept for reuse. A client is taken with `get` and goes
+/// back when the returned guard is dropped.
+pub struct Pool<C> {
+    config: ClientConfig,
+    idle: Mutex<Vec<C>>,
+    max_idle: usize,
+}
+
+/// A client borrowed from a `Pool`.
+pub struct Pooled<'a, C: Poolable> {
+    pool: &'a Pool<C>,
+    client: Option<C>,
+}
+
+impl<C: Poolable> Pool<C> {
+    /// A pool that keeps at most `max_idle` clients between uses.
+    pub fn new(config: ClientConfig, max_idle: usize) -> Pool<C> {
+        Pool {
+            config,
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+            max_idle,
+        }
+    }
+
+    /// Clients waiting for reuse.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn take(&self) -> Option<C> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn pooled(&self, client: C) -> Pooled<'_, C> {
+        Pooled {
+            pool: self,
+            client: Some(client),
+        }
+    }
+}
+
+impl Pool<Client> {
+    /// An idle client, or a newly connected one if none is left.
+    pub fn get(&self) -> Result<Pooled<'_, Client>> {
+        let client = match self.take() {
+            Some(client) => client,
+            None => Client::connect(self.config.clone())?,
+        };
+        Ok(self.pooled(client))
+    }
+}
+
+#[cfg(feature = "async")]
+impl Pool<crate::AsyncClient> {
+    /// An idle client, or a newly connected one if none is left.
+    pub async fn get(&self) -> Result<Pooled<'_, crate::AsyncClient>> {
+        let client = match self.take() {
+            Some(client) => client,
+            None => crate::AsyncClient::connect(self.config.clone()).await?,
+        };
+        Ok(self.pooled(client))
+    }
+}
+
+impl<'a, C: Poolable> Deref for Pooled<'a, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<'a, C: Poolable> DerefMut for Pooled<'a, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<'a, C: Poolable> Drop for Pooled<'a, C> {
+    fn drop(&mut self) {
+        let client = match self.client.take() {
+            Some(client) if client.is_connected() => client,
+            _ => return,
+        };
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.max_idle {
+            idle.push(client);
+        }
+    }
+}
+
+#[test]
+fn test_pool_reuse() {
+    use crate::test_server;
+    use tantivy_server_protocol::IndexQuery;
+
+    let server = test_server::start(usize::MAX);
+    let pool: Pool<Client> = Pool::new(ClientConfig::new(server.addr.clone()), 1);
+    let query = IndexQuery {
+        index: "book".to_string(),
+        param: "*".to_string(),
+        size: 1,
+        offset: 0,
+        highlight: false,
+    };
+    {
+        let mut first = pool.get().unwrap();
+        let mut second = pool.get().unwrap();
+        first.search(&query).unwrap();
+        second.search(&query).unwrap();
+    }
+    // Only one of the two is kept.
+    assert_eq!(pool.idle(), 1);
+    pool.get().unwrap().search(&query).unwrap();
+    assert_eq!(server.connections(), 2);
+}
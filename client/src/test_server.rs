@@ -0,0 +1,133 @@
+//! A stand-in server speaking just enough of the protocol for the client
+//! tests: it answers `Hello` and `Search`, fails every other command, and
+//! drops each connection after a set number of searches.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tantivy_server_protocol::{
+    ByteOrder, Cmd, Compression, DocValue, Encoding, ErrorReply, Framing, Handshake, Hello,
+    IndexQuery, Limits, Payload, Response, SearchResult, Status, GREETING, PROTOCOL_V2,
+};
+
+pub struct TestServer {
+    pub addr: String,
+    connections: Arc<AtomicUsize>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    id: Option<Value>,
+    cmd: Cmd,
+}
+
+#[derive(Deserialize)]
+struct WithBody<B> {
+    body: B,
+}
+
+impl TestServer {
+    /// Connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Listens on a free port. Each connection is dropped after answering
+/// `searches` searches.
+pub fn start(searches: usize) -> TestServer {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let conn = counter.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+            let stream = stream.unwrap();
+            thread::spawn(move || serve(stream, conn, searches));
+        }
+    });
+    TestServer { addr, connections }
+}
+
+fn serve(mut stream: TcpStream, conn: u64, mut searches: usize) {
+    use std::io::Write;
+
+    let mut framing = Framing::new(ByteOrder::Little);
+    while searches > 0 {
+        let frame = match framing.read(&mut stream, u32::MAX) {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+        let header: Header = framing.decode(&frame).unwrap();
+        let result = match header.cmd {
+            Cmd::Hello => {
+                let hello = framing.decode::<WithBody<Hello>>(&frame).unwrap().body;
+                framing.encoding = hello.encoding.unwrap_or(framing.encoding);
+                framing.compression = hello.compression.unwrap_or(framing.compression);
+                Ok(Payload::Hello(handshake(&framing)))
+            }
+            Cmd::Search => {
+                let query = framing.decode::<WithBody<IndexQuery>>(&frame).unwrap().body;
+                searches -= 1;
+                let mut hit = HashMap::new();
+                hit.insert("conn".to_string(), DocValue::U64(conn));
+                hit.insert("param".to_string(), DocValue::from(query.param));
+                Ok(Payload::Search(SearchResult {
+                    total: 1,
+                    hits: vec![hit],
+                    scores: vec![],
+                }))
+            }
+            _ => Err(ErrorReply {
+                kind: "IndexNotFound".to_string(),
+                message: "IndexNotFound: missing".to_string(),
+                field: None,
+                position: None,
+            }),
+        };
+        let response = match result {
+            Ok(payload) => Response {
+                id: header.id,
+                status: Status::Ok,
+                code: 0,
+                took_ms: 0,
+                payload: Some(payload),
+                error: None,
+            },
+            Err(error) => Response {
+                id: header.id,
+                status: Status::Wrong,
+                code: 200,
+                took_ms: 0,
+                payload: None,
+                error: Some(error),
+            },
+        };
+        stream.write_all(&framing.encode(&response)).unwrap();
+    }
+}
+
+fn handshake(framing: &Framing) -> Handshake {
+    Handshake {
+        greeting: GREETING.to_string(),
+        version: "test".to_string(),
+        protocols: vec![PROTOCOL_V2],
+        encodings: vec![Encoding::Json, Encoding::Msgpack, Encoding::Cbor],
+        compressions: vec![Compression::None, Compression::Lz4, Compression::Zstd],
+        protocol: PROTOCOL_V2,
+        byteorder: framing.byteorder,
+        encoding: framing.encoding,
+        compression: framing.compression,
+        auth: false,
+        limits: Limits {
+            max_frame_bytes: u32::MAX as u64,
+            max_page_size: 100,
+            compress_threshold_bytes: framing.compression_conf.threshold_bytes,
+        },
+    }
+}
@@ -0,0 +1,170 @@
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A document field value as it travels in a request or reply. Unlike a
+/// JSON value it can hold bytes, which binary encodings carry natively and
+/// JSON as a base64 string.
+#[derive(Clone, PartialEq, Debug)]
+pub enum DocValue {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Array(Vec<DocValue>),
+    Object(BTreeMap<String, DocValue>),
+}
+
+impl DocValue {
+    /// The value as JSON, with bytes as base64 text.
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            DocValue::Null => JsonValue::Null,
+            DocValue::Bool(b) => JsonValue::from(*b),
+            DocValue::I64(n) => JsonValue::from(*n),
+            DocValue::U64(n) => JsonValue::from(*n),
+            DocValue::F64(n) => JsonValue::from(*n),
+            DocValue::Str(s) => JsonValue::from(s.as_str()),
+            DocValue::Bytes(b) => JsonValue::from(base64::encode(b)),
+            DocValue::Array(items) => items.iter().map(DocValue::to_json).collect(),
+            DocValue::Object(map) => map.iter().map(|(k, v)| (k.clone(), v.to_json())).collect(),
+        }
+    }
+}
+
+impl From<String> for DocValue {
+    fn from(s: String) -> DocValue {
+        DocValue::Str(s)
+    }
+}
+
+impl Serialize for DocValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            DocValue::Null => serializer.serialize_unit(),
+            DocValue::Bool(b) => serializer.serialize_bool(*b),
+            DocValue::I64(n) => serializer.serialize_i64(*n),
+            DocValue::U64(n) => serializer.serialize_u64(*n),
+            DocValue::F64(n) => serializer.serialize_f64(*n),
+            DocValue::Str(s) => serializer.serialize_str(s),
+            DocValue::Bytes(b) if serializer.is_human_readable() => {
+                serializer.serialize_str(&base64::encode(b))
+            }
+            DocValue::Bytes(b) => serializer.serialize_bytes(b),
+            DocValue::Array(items) => items.serialize(serializer),
+            DocValue::Object(map) => map.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DocValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct DocValueVisitor;
+
+        impl<'de> Visitor<'de> for DocValueVisitor {
+            type Value = DocValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a document field value")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Null)
+            }
+
+            fn visit_none<E: de::Error>(self) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Null)
+            }
+
+            fn visit_some<D: Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<DocValue, D::Error> {
+                DocValue::deserialize(deserializer)
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Bool(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::I64(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::U64(v))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::F64(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Str(v.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Str(v))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Bytes(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> std::result::Result<DocValue, E> {
+                Ok(DocValue::Bytes(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<DocValue, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(DocValue::Array(items))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<DocValue, A::Error> {
+                let mut object = BTreeMap::new();
+                while let Some((k, v)) = map.next_entry()? {
+                    object.insert(k, v);
+                }
+                Ok(DocValue::Object(object))
+            }
+        }
+
+        deserializer.deserialize_any(DocValueVisitor)
+    }
+}
+
+#[test]
+fn test_doc_value_bytes() {
+    let value = DocValue::Bytes(vec![0, 1, 255]);
+    // JSON carries bytes as base64 text, binary encodings as bytes.
+    assert_eq!(serde_json::to_string(&value).unwrap(), r#""AAH/""#);
+    let packed = rmp_serde::to_vec(&value).unwrap();
+    assert_eq!(rmp_serde::from_slice::<DocValue>(&packed).unwrap(), value);
+    let packed = serde_cbor::to_vec(&value).unwrap();
+    assert_eq!(serde_cbor::from_slice::<DocValue>(&packed).unwrap(), value);
+
+    let doc: std::collections::HashMap<String, DocValue> =
+        serde_json::from_str(r#"{"n": -1, "u": 2, "f": 0.5, "s": "x", "a": [1, null]}"#).unwrap();
+    assert_eq!(doc["n"], DocValue::I64(-1));
+    assert_eq!(doc["u"], DocValue::U64(2));
+    assert_eq!(doc["f"], DocValue::F64(0.5));
+    assert_eq!(doc["s"], DocValue::from("x".to_string()));
+    assert_eq!(
+        doc["a"],
+        DocValue::Array(vec![DocValue::U64(1), DocValue::Null])
+    );
+}
@@ -0,0 +1,46 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A frame that could not be read or decoded.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The length prefix is over the reader's limit.
+    FrameTooLarge {
+        len: u32,
+        max: u32,
+    },
+    /// The frame is malformed: bad compression, or a message that does not
+    /// decode into the expected type.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", len, max)
+            }
+            Error::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
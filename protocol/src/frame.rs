@@ -0,0 +1,346 @@
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Flag byte values that lead each frame once compression is negotiated.
+const FLAG_NONE: u8 = 0;
+const FLAG_LZ4: u8 = 1;
+const FLAG_ZSTD: u8 = 2;
+
+/// A connection's first length prefix is taken in the other byte order when
+/// it reads as this much or more in the expected one.
+const FIRST_FRAME_LIMIT: u32 = 1 << 24;
+
+/// Byte order of the 4-byte frame length prefix.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+/// How frames are serialized. Binary document values travel as bytes in
+/// MessagePack and CBOR, and as base64 strings in JSON.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+/// Frame compression. With anything but `None`, every frame starts with a
+/// flag byte telling how the rest of it is compressed.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    /// LZ4 frame format.
+    Lz4,
+    Zstd,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Debug)]
+pub struct CompressionConf {
+    /// Frames smaller than this are sent uncompressed.
+    #[serde(default = "default_threshold_bytes")]
+    pub threshold_bytes: usize,
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+}
+
+fn default_threshold_bytes() -> usize {
+    4096
+}
+
+fn default_zstd_level() -> i32 {
+    3
+}
+
+/// The per-connection settings that decide how a message becomes a frame.
+/// Both ends start from JSON without compression and switch together when
+/// a `Hello` is answered.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Framing {
+    pub byteorder: ByteOrder,
+    pub encoding: Encoding,
+    pub compression: Compression,
+    pub compression_conf: CompressionConf,
+}
+
+impl Default for CompressionConf {
+    fn default() -> CompressionConf {
+        CompressionConf {
+            threshold_bytes: default_threshold_bytes(),
+            zstd_level: default_zstd_level(),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn to_vec<T: Serialize>(self, msg: &T) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(msg).unwrap(),
+            Encoding::Msgpack => rmp_serde::to_vec_named(msg).unwrap(),
+            Encoding::Cbor => serde_cbor::to_vec(msg).unwrap(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => {
+                serde_json::from_slice(buf).map_err(|e| Error::Invalid(e.to_string()))
+            }
+            Encoding::Msgpack => {
+                rmp_serde::from_slice(buf).map_err(|e| Error::Invalid(e.to_string()))
+            }
+            Encoding::Cbor => {
+                serde_cbor::from_slice(buf).map_err(|e| Error::Invalid(e.to_string()))
+            }
+        }
+    }
+}
+
+impl ByteOrder {
+    pub fn len_bytes(self, len: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Big => len.to_be_bytes(),
+            ByteOrder::Little => len.to_le_bytes(),
+        }
+    }
+
+    pub fn read_len(self, buf: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::Big => u32::from_be_bytes(buf),
+            ByteOrder::Little => u32::from_le_bytes(buf),
+        }
+    }
+
+    pub fn other(self) -> ByteOrder {
+        match self {
+            ByteOrder::Big => ByteOrder::Little,
+            ByteOrder::Little => ByteOrder::Big,
+        }
+    }
+
+    /// The order the first length prefix of a connection was written in:
+    /// `self`, unless it reads as 16 MiB or more that way and less the other
+    /// way. A frame below 16 MiB whose length is not a multiple of 256, as
+    /// `Framing::encode_first` makes it, always reads as 16 MiB or more in
+    /// the wrong order.
+    pub fn detect(self, prefix: [u8; 4]) -> ByteOrder {
+        if self.read_len(prefix) >= FIRST_FRAME_LIMIT
+            && self.other().read_len(prefix) < FIRST_FRAME_LIMIT
+        {
+            self.other()
+        } else {
+            self
+        }
+    }
+}
+
+impl Compression {
+    /// Adds the flag byte to an encoded frame, compressing it when it is at
+    /// least `conf.threshold_bytes` long.
+    pub fn pack(self, frame: Vec<u8>, conf: &CompressionConf) -> Vec<u8> {
+        if self == Compression::None {
+            return frame;
+        }
+        let mut buf = Vec::with_capacity(frame.len() + 1);
+        if frame.len() < conf.threshold_bytes {
+            buf.push(FLAG_NONE);
+            buf.extend_from_slice(&frame);
+            return buf;
+        }
+        match self {
+            Compression::Lz4 => {
+                buf.push(FLAG_LZ4);
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(buf);
+                encoder.write_all(&frame).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd | Compression::None => {
+                buf.push(FLAG_ZSTD);
+                zstd::stream::copy_encode(&frame[..], &mut buf, conf.zstd_level).unwrap();
+                buf
+            }
+        }
+    }
+
+    /// Strips the flag byte and decompresses the frame. Either algorithm is
+    /// accepted, whichever was negotiated; a frame that inflates past `max`
+    /// bytes is refused.
+    pub fn unpack(self, frame: Vec<u8>, max: u32) -> Result<Vec<u8>> {
+        if self == Compression::None {
+            return Ok(frame);
+        }
+        let (flag, body) = match frame.split_first() {
+            Some((flag, body)) => (*flag, body),
+            None => return Err(Error::Invalid("empty frame".to_string())),
+        };
+        let decoder: Box<dyn Read + '_> = match flag {
+            FLAG_NONE => return Ok(body.to_vec()),
+            FLAG_LZ4 => Box::new(lz4_flex::frame::FrameDecoder::new(body)),
+            FLAG_ZSTD => Box::new(zstd::stream::read::Decoder::new(body)?),
+            flag => return Err(Error::Invalid(format!("unknown compression flag {}", flag))),
+        };
+        let mut buf = Vec::new();
+        decoder
+            .take(max as u64 + 1)
+            .read_to_end(&mut buf)
+            .map_err(|e| Error::Invalid(format!("bad compressed frame: {}", e)))?;
+        if buf.len() > max as usize {
+            return Err(Error::Invalid(format!(
+                "frame decompresses to more than {} bytes",
+                max
+            )));
+        }
+        Ok(buf)
+    }
+}
+
+impl Framing {
+    /// JSON, uncompressed, with lengths in `byteorder`: what a connection
+    /// speaks until a `Hello` changes it.
+    pub fn new(byteorder: ByteOrder) -> Framing {
+        Framing {
+            byteorder,
+            encoding: Encoding::Json,
+            compression: Compression::None,
+            compression_conf: CompressionConf::default(),
+        }
+    }
+
+    /// Serializes `msg` into a whole frame, length prefix included.
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Vec<u8> {
+        let msg = self
+            .compression
+            .pack(self.encoding.to_vec(msg), &self.compression_conf);
+        let mut buf = Vec::with_capacity(4 + msg.len());
+        buf.extend_from_slice(&self.byteorder.len_bytes(msg.len() as u32));
+        buf.extend_from_slice(&msg);
+        buf
+    }
+
+    /// Like `encode`, for the first frame of a connection, which is JSON
+    /// without compression: a trailing space keeps its length off multiples
+    /// of 256, so the other end can tell its byte order.
+    pub fn encode_first<T: Serialize>(&self, msg: &T) -> Vec<u8> {
+        let mut msg = serde_json::to_vec(msg).unwrap();
+        if msg.len().is_multiple_of(256) {
+            msg.push(b' ');
+        }
+        let mut buf = Vec::with_capacity(4 + msg.len());
+        buf.extend_from_slice(&self.byteorder.len_bytes(msg.len() as u32));
+        buf.extend_from_slice(&msg);
+        buf
+    }
+
+    /// Like `frame_len`, for the first frame of a connection: switches to
+    /// the byte order `prefix` was written in.
+    pub fn first_frame_len(&mut self, prefix: [u8; 4], max: u32) -> Result<usize> {
+        self.byteorder = self.byteorder.detect(prefix);
+        self.frame_len(prefix, max)
+    }
+
+    /// The length of the frame behind `prefix`, if it is at most `max`.
+    pub fn frame_len(&self, prefix: [u8; 4], max: u32) -> Result<usize> {
+        let len = self.byteorder.read_len(prefix);
+        if len > max {
+            return Err(Error::FrameTooLarge { len, max });
+        }
+        Ok(len as usize)
+    }
+
+    /// Reads one frame and undoes its compression, leaving the message
+    /// still encoded.
+    pub fn read<R: Read>(&self, reader: &mut R, max: u32) -> Result<Vec<u8>> {
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix)?;
+        let mut buf = vec![0u8; self.frame_len(prefix, max)?];
+        reader.read_exact(&mut buf)?;
+        self.compression.unpack(buf, max)
+    }
+
+    /// Like `read`, for the first frame of a connection: switches to the byte order it was written in.
+    pub fn read_first<R: Read>(&mut self, reader: &mut R, max: u32) -> Result<Vec<u8>> {
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix)?;
+        let mut buf = vec![0u8; self.first_frame_len(prefix, max)?];
+        reader.read_exact(&mut buf)?;
+        self.compression.unpack(buf, max)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, buf: &[u8]) -> Result<T> {
+        self.encoding.decode(buf)
+    }
+}
+
+#[test]
+fn test_compression() {
+    let conf = CompressionConf {
+        threshold_bytes: 64,
+        zstd_level: 3,
+    };
+    let small = b"{\"cmd\":\"Hello\"}".to_vec();
+    let large = "{\"text\":\"tantivy\"}".repeat(100).into_bytes();
+    assert_eq!(Compression::None.pack(large.clone(), &conf), large);
+    for compression in [Compression::Lz4, Compression::Zstd].iter() {
+        let packed = compression.pack(small.clone(), &conf);
+        assert_eq!(packed[0], FLAG_NONE);
+        assert_eq!(compression.unpack(packed, 1024).unwrap(), small);
+
+        let packed = compression.pack(large.clone(), &conf);
+        assert_ne!(packed[0], FLAG_NONE);
+        assert!(packed.len() < large.len() / 4);
+        assert_eq!(compression.unpack(packed.clone(), 4096).unwrap(), large);
+        // The cap applies to the decompressed size.
+        assert!(compression.unpack(packed, 1024).is_err());
+    }
+    assert!(Compression::Lz4.unpack(vec![9, 1, 2], 1024).is_err());
+}
+
+#[test]
+fn test_framing() {
+    let mut framing = Framing::new(ByteOrder::Big);
+    framing.encoding = Encoding::Msgpack;
+    framing.compression = Compression::Zstd;
+    framing.compression_conf.threshold_bytes = 0;
+    let msg = vec!["tantivy".to_string(); 50];
+    let frame = framing.encode(&msg);
+    assert_eq!(
+        framing
+            .byteorder
+            .read_len([frame[0], frame[1], frame[2], frame[3]]) as usize,
+        frame.len() - 4
+    );
+    let buf = framing.read(&mut &frame[..], 1024).unwrap();
+    assert_eq!(framing.decode::<Vec<String>>(&buf).unwrap(), msg);
+    match framing.read(&mut &frame[..], 8) {
+        Err(Error::FrameTooLarge { max: 8, .. }) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_first_frame() {
+    // 254 bytes of JSON, then 256 with the quotes around the string.
+    for len in [254, 256].iter() {
+        let msg = "a".repeat(len - 2);
+        for order in [ByteOrder::Little, ByteOrder::Big].iter() {
+            let frame = Framing::new(*order).encode_first(&msg);
+            assert!(!(frame.len() - 4).is_multiple_of(256));
+            for expected in [ByteOrder::Little, ByteOrder::Big].iter() {
+                let mut framing = Framing::new(*expected);
+                let buf = framing.read_first(&mut &frame[..], 1 << 30).unwrap();
+                assert_eq!(framing.byteorder, *order);
+                assert_eq!(framing.decode::<String>(&buf).unwrap(), msg);
+            }
+        }
+    }
+    // A frame in the expected order keeps it, whatever its length.
+    let prefix = ByteOrder::Big.len_bytes(512);
+    assert_eq!(ByteOrder::Big.detect(prefix), ByteOrder::Big);
+}
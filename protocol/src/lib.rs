@@ -0,0 +1,33 @@
+//! The tantivy-server wire protocol: frame layout, request bodies and
+//! replies, shared by the server and its clients.
+//!
+//! A frame is a 4-byte length in the connection's byte order followed by
+//! the message in the connection's encoding, with a leading compression
+//! flag byte once a compression is negotiated. See [`Framing`].
+
+mod doc;
+mod error;
+mod frame;
+mod request;
+mod response;
+
+pub use doc::DocValue;
+pub use error::{Error, Result};
+pub use frame::{ByteOrder, Compression, CompressionConf, Encoding, Framing};
+pub use request::{
+    Cmd, FieldOption, FieldSchema, Hello, IndexData, IndexName, IndexQuery, IndexSchema,
+    MergeQuery, QueryItem, Record, Request, StatsQuery, Tokenizer, UploadBegin, UploadChunk,
+    UploadEnd,
+};
+pub use response::{
+    AddResult, DeleteResult, ErrorReply, Handshake, IndexInfo, IndexStats, Limits, MergeResult,
+    Message, Payload, Response, SearchResult, SegmentInfo, ServerStats, Status,
+};
+
+/// The original protocol: bare `Message` replies, and `Search` answers with a
+/// result frame followed by an `Ok` frame.
+pub const PROTOCOL_V1: u8 = 1;
+/// Every request is answered with exactly one `Response` envelope.
+pub const PROTOCOL_V2: u8 = 2;
+
+pub const GREETING: &str = "Tantivy Search Engine";
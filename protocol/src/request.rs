@@ -0,0 +1,161 @@
+use crate::doc::DocValue;
+use crate::frame::{ByteOrder, Compression, Encoding};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum Cmd {
+    Hello,
+    Create,
+    Add,
+    Search,
+    Delete,
+    Describe,
+    UploadBegin,
+    UploadChunk,
+    UploadEnd,
+    Stats,
+    Commit,
+    Merge,
+}
+
+/// A request frame as a client builds it.
+#[derive(Serialize, Debug)]
+pub struct Request<B> {
+    /// Optional client-supplied correlation id, echoed back in every reply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub cmd: Cmd,
+    /// API key for this request alone, overriding the one given in `Hello`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub body: B,
+}
+
+/// The capabilities a client may ask for in a `Hello`. Omitted fields keep
+/// the connection's current setting.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Hello {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byteorder: Option<ByteOrder>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// API key for every later request on the connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// The `Create` body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexSchema {
+    pub index: String,
+    pub field: Vec<FieldSchema>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Record {
+    Basic,
+    Freq,
+    Position,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Tokenizer {
+    EnStem,
+    Jieba,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FieldOption {
+    pub stored: bool,
+    pub fast: bool,
+    pub indexed: bool,
+    pub record: Option<Record>, // basic/freq/position
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FieldSchema {
+    pub name: String,
+    /// `TEXT`, `U64`, `I64`, `F64`, `DATE`, `FACET` or `BYTES`.
+    pub typ: String,
+    pub tokenizer: Option<Tokenizer>,
+    pub option: FieldOption,
+}
+
+/// The `Add` body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexData {
+    pub index: String,
+    pub data: Vec<HashMap<String, DocValue>>,
+}
+
+/// The `Search` body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexQuery {
+    pub index: String,
+    /// Query in tantivy's query syntax.
+    pub param: String,
+    pub size: usize,
+    pub offset: usize,
+    /// Return snippets for the tokenized text fields in place of their
+    /// stored values.
+    #[serde(default = "default_highlight")]
+    pub highlight: bool,
+}
+
+fn default_highlight() -> bool {
+    true
+}
+
+/// The `Delete` body. An empty `field` deletes every document.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueryItem {
+    pub index: String,
+    pub field: String,
+    pub text: String,
+}
+
+/// The `Describe` and `Commit` body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexName {
+    pub index: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadBegin {
+    pub index: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadChunk {
+    pub data: Vec<HashMap<String, DocValue>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadEnd {}
+
+/// The `Merge` body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MergeQuery {
+    pub index: String,
+    /// How many segments may be left.
+    #[serde(default = "default_max_segments")]
+    pub max_segments: usize,
+}
+
+fn default_max_segments() -> usize {
+    1
+}
+
+/// The `Stats` body.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct StatsQuery {
+    /// Only this index, rather than every index the caller may read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+}
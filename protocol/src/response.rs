@@ -0,0 +1,206 @@
+use crate::doc::DocValue;
+use crate::frame::{ByteOrder, Compression, Encoding};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+    Ok,
+    Wrong,
+}
+
+/// The v1 reply.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Message {
+    /// The `id` of the request this message replies to, if the client sent one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub status: Status,
+    /// The server error code, on failure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(default)]
+    pub message: Option<Value>,
+}
+
+/// The v2 reply envelope.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub status: Status,
+    /// `0` on success, the server error code otherwise.
+    pub code: u16,
+    pub took_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Payload>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReply>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorReply {
+    pub kind: String,
+    pub message: String,
+    /// The document field that failed to parse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Byte offset of the error in the query string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+}
+
+/// The typed result of a command.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Hello(Handshake),
+    Created {},
+    Added(AddResult),
+    Deleted(DeleteResult),
+    Search(SearchResult),
+    Describe(IndexInfo),
+    /// Progress of a chunked upload: documents received so far.
+    Upload {
+        docs: usize,
+    },
+    Stats(ServerStats),
+    Committed {
+        opstamp: u64,
+    },
+    Merged(MergeResult),
+}
+
+/// Sent on connect when the server has `handshake_on_connect` set, and in
+/// reply to `Hello`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    pub greeting: String,
+    pub version: String,
+    pub protocols: Vec<u8>,
+    pub encodings: Vec<Encoding>,
+    pub compressions: Vec<Compression>,
+    /// The settings in effect for this connection.
+    pub protocol: u8,
+    pub byteorder: ByteOrder,
+    pub encoding: Encoding,
+    pub compression: Compression,
+    /// Whether commands need an API key.
+    pub auth: bool,
+    pub limits: Limits,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Limits {
+    pub max_frame_bytes: u64,
+    pub max_page_size: usize,
+    /// Replies at least this long are compressed, once a compression is
+    /// negotiated.
+    pub compress_threshold_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddResult {
+    /// The opstamp of the commit that made the documents searchable. When
+    /// the index's commit policy holds the commit back, the opstamp of the
+    /// last document instead: the documents are searchable once a commit
+    /// with a greater opstamp is made.
+    pub opstamp: u64,
+    pub docs: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteResult {
+    /// Like `AddResult::opstamp`.
+    pub opstamp: u64,
+    /// Number of documents removed by the delete.
+    pub deleted: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MergeResult {
+    /// The opstamp of the last commit, including any made before merging.
+    pub opstamp: u64,
+    /// Number of segments left.
+    pub segments: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResult {
+    pub total: usize,
+    pub hits: Vec<HashMap<String, DocValue>>,
+    /// Score of each hit, in the same order. Only filled in on the server.
+    #[serde(skip)]
+    pub scores: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexInfo {
+    pub index: String,
+    /// The tantivy schema, in its JSON form.
+    pub schema: Value,
+    /// Opstamp of the last commit.
+    pub opstamp: u64,
+    pub num_docs: u64,
+    pub segments: Vec<SegmentInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SegmentInfo {
+    pub id: String,
+    pub num_docs: u32,
+    pub num_deleted_docs: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerStats {
+    pub version: String,
+    pub uptime_secs: u64,
+    /// The indices under `base_dir`, by name.
+    pub indices: Vec<IndexStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexStats {
+    pub index: String,
+    pub num_docs: u64,
+    pub num_deleted_docs: u64,
+    pub segments: usize,
+}
+
+impl SearchResult {
+    /// The `{"Total", "Data"}` shape returned by protocol v1.
+    pub fn into_v1(self) -> HashMap<String, Value> {
+        let mut result: HashMap<String, Value> = HashMap::with_capacity(2);
+        result.insert(
+            "Total".to_string(),
+            serde_json::to_value(self.total).unwrap(),
+        );
+        result.insert("Data".to_string(), serde_json::to_value(self.hits).unwrap());
+        result
+    }
+}
+
+#[test]
+fn test_payload_round_trip() {
+    let json = r#"{"status":"Ok","code":0,"took_ms":3,
+        "payload":{"search":{"total":1,"hits":[{"title":"Of Mice and Men"}]}}}"#;
+    let response: Response = serde_json::from_str(json).unwrap();
+    match response.payload {
+        Some(Payload::Search(result)) => {
+            assert_eq!(result.total, 1);
+            assert_eq!(
+                result.hits[0]["title"],
+                DocValue::from("Of Mice and Men".to_string())
+            );
+        }
+        other => panic!("{:?}", other),
+    }
+    let created = serde_json::to_string(&Payload::Created {}).unwrap();
+    assert_eq!(created, r#"{"created":{}}"#);
+    assert!(matches!(
+        serde_json::from_str::<Payload>(&created).unwrap(),
+        Payload::Created {}
+    ));
+}
@@ -0,0 +1,140 @@
+use crate::CONF;
+use serde::{Deserialize, Serialize};
+use tantivy_server::{Result, ServerError};
+
+/// API keys. Without an `auth` section in `app.yml` every client may run
+/// every command.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AuthConf {
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ApiKey {
+    /// Shown in the logs in place of the token.
+    pub name: String,
+    pub token: String,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Grant {
+    pub role: Role,
+    /// Index name patterns, where `*` matches any run of characters.
+    #[serde(default = "all_indices")]
+    pub indices: Vec<String>,
+}
+
+fn all_indices() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+/// What a command needs; each role includes the ones before it.
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// `Search` and `Describe`.
+    Read,
+    /// `Add`, uploads, `Commit`, and `Delete` by term.
+    Write,
+    /// `Create`, `Merge`, and `Delete` of every document.
+    Admin,
+}
+
+/// Finds the key for `token`. `None` when authentication is off.
+pub fn authenticate(token: &str) -> Result<Option<&'static ApiKey>> {
+    let conf = match CONF.auth {
+        Some(ref conf) => conf,
+        None => return Ok(None),
+    };
+    conf.keys
+        .iter()
+        .find(|key| constant_time_eq(key.token.as_bytes(), token.as_bytes()))
+        .map(Some)
+        .ok_or_else(|| ServerError::Unauthorized("unknown token".to_string()))
+}
+
+/// Checks that the caller may act as `role` on `index`.
+pub fn authorize(key: Option<&ApiKey>, role: Role, index: &str) -> Result<()> {
+    if CONF.auth.is_none() {
+        return Ok(());
+    }
+    let key = key.ok_or_else(|| ServerError::Unauthorized("token required".to_string()))?;
+    if key.allows(role, index) {
+        Ok(())
+    } else {
+        Err(ServerError::Forbidden(format!(
+            "key {} has no {:?} grant on index {}",
+            key.name, role, index
+        )))
+    }
+}
+
+impl ApiKey {
+    fn allows(&self, role: Role, index: &str) -> bool {
+        self.grants.iter().any(|grant| {
+            grant.role >= role
+                && grant
+                    .indices
+                    .iter()
+                    .any(|pattern| glob_match(pattern, index))
+        })
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        // No `*` in the pattern.
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_grants() {
+    assert!(glob_match("*", "book"));
+    assert!(glob_match("logs-*", "logs-2021"));
+    assert!(!glob_match("logs-*", "book"));
+    assert!(glob_match("a*c*e", "abcde"));
+    assert!(!glob_match("a*c*e", "abcd"));
+    assert!(glob_match("book", "book"));
+    assert!(!glob_match("book", "books"));
+
+    let key = ApiKey {
+        name: "shipper".to_string(),
+        token: "secret".to_string(),
+        grants: vec![
+            Grant {
+                role: Role::Write,
+                indices: vec!["logs-*".to_string()],
+            },
+            Grant {
+                role: Role::Read,
+                indices: all_indices(),
+            },
+        ],
+    };
+    assert!(key.allows(Role::Write, "logs-app"));
+    assert!(key.allows(Role::Read, "book"));
+    assert!(!key.allows(Role::Write, "book"));
+    assert!(!key.allows(Role::Admin, "logs-app"));
+}
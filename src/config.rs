@@ -0,0 +1,358 @@
+use crate::auth::AuthConf;
+use crate::http::HttpConf;
+use crate::stream::UnixSocketConf;
+use crate::tls::TlsConf;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tantivy_server::{CommitPolicy, IndexConf, LogMergeConf, MergePolicyConf};
+use tantivy_server_protocol::CompressionConf;
+
+/// Command-line flags. Each may also be given as a `TANTIVY_SERVER_*`
+/// environment variable, and overrides the config file.
+#[derive(Parser, Debug)]
+#[clap(
+    version,
+    about = "A tantivy search server speaking a framed TCP protocol"
+)]
+pub struct Args {
+    /// The YAML config file.
+    #[clap(long, env = "TANTIVY_SERVER_CONFIG", default_value = "config/app.yml")]
+    pub config: String,
+    /// Address of the TCP listener, in place of `bind_addr`.
+    #[clap(long, env = "TANTIVY_SERVER_BIND")]
+    pub bind: Option<String>,
+    /// Directory of the indices, in place of `index.base_dir`.
+    #[clap(long, env = "TANTIVY_SERVER_BASE_DIR")]
+    pub base_dir: Option<String>,
+    /// The log4rs config file, in place of `log_config`.
+    #[clap(long, env = "TANTIVY_SERVER_LOG_CONFIG")]
+    pub log_config: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    pub bind_addr: String,
+    pub byteorder: String,
+    /// Send the handshake frame as soon as a client connects, rather than
+    /// only in reply to `Hello`.
+    #[serde(default)]
+    pub handshake_on_connect: bool,
+    /// Largest request frame accepted; bigger bulk loads go through a
+    /// chunked upload.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: u32,
+    /// When a negotiated compression kicks in.
+    #[serde(default)]
+    pub compression: CompressionConf,
+    /// Threads serving TCP connections, one connection each at a time.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Connections served or waiting for a worker; clients beyond this are
+    /// refused with `TooManyConnections`.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Seconds an admitted connection may wait for a free worker before it
+    /// is refused with `TooManyConnections`.
+    #[serde(default = "default_queue_timeout_secs")]
+    pub queue_timeout_secs: u64,
+    /// Seconds a connection may sit without sending a request before it is
+    /// closed.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Seconds to let in-flight requests finish after SIGTERM/SIGINT before
+    /// exiting anyway.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    pub log_config: String,
+    /// Optional Unix socket listener, served like the TCP one.
+    #[serde(default)]
+    pub unix_socket: Option<UnixSocketConf>,
+    /// Serve the TCP listener over TLS.
+    #[serde(default)]
+    pub tls: Option<TlsConf>,
+    /// API keys and their grants; without it anyone may run any command.
+    #[serde(default)]
+    pub auth: Option<AuthConf>,
+    /// Optional HTTP/JSON listener next to the TCP one.
+    #[serde(default)]
+    pub http: Option<HttpConf>,
+    pub index: IndexConf,
+}
+
+fn default_max_frame_bytes() -> u32 {
+    16 * 1024 * 1024
+}
+
+fn default_workers() -> usize {
+    64
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_queue_timeout_secs() -> u64 {
+    5
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+/// Why the server could not start with its configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: String,
+        source: io::Error,
+    },
+    Parse {
+        path: String,
+        source: serde_yaml::Error,
+    },
+    /// A file the config names does not exist.
+    Missing {
+        field: &'static str,
+        path: String,
+    },
+    Invalid {
+        field: String,
+        msg: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file {}: {}", path, source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file {}: {}", path, source)
+            }
+            ConfigError::Missing { field, path } => {
+                write!(f, "{}: file {} does not exist", field, path)
+            }
+            ConfigError::Invalid { field, msg } => write!(f, "{}: {}", field, msg),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the config file named in `args` and applies the overrides.
+pub fn load(args: &Args) -> Result<Config, ConfigError> {
+    let text = fs::read_to_string(&args.config).map_err(|source| ConfigError::Read {
+        path: args.config.clone(),
+        source,
+    })?;
+    let mut config: Config = serde_yaml::from_str(&text).map_err(|source| ConfigError::Parse {
+        path: args.config.clone(),
+        source,
+    })?;
+    if let Some(ref bind) = args.bind {
+        config.bind_addr = bind.clone();
+    }
+    if let Some(ref base_dir) = args.base_dir {
+        config.index.base_dir = base_dir.clone();
+    }
+    if let Some(ref log_config) = args.log_config {
+        config.log_config = log_config.clone();
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    /// Catches the settings that would otherwise fail, or be ignored, only
+    /// once the server is running.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.byteorder != "little" && self.byteorder != "big" {
+            return Err(invalid(
+                "byteorder",
+                format!("must be little or big, not {:?}", self.byteorder),
+            ));
+        }
+        for &(field, value) in &[
+            ("workers", self.workers),
+            ("max_connections", self.max_connections),
+            ("max_frame_bytes", self.max_frame_bytes as usize),
+            ("index.total_heap_size", self.index.total_heap_size),
+            ("index.max_page_size", self.index.max_page_size),
+            ("index.writer_queue_size", self.index.writer_queue_size),
+            (
+                "index.upload_idle_secs",
+                self.index.upload_idle_secs as usize,
+            ),
+        ] {
+            if value == 0 {
+                return Err(invalid(field, "must be greater than 0".to_string()));
+            }
+        }
+        let mut sections = vec![(
+            "index".to_string(),
+            Some(self.index.commit),
+            self.index.merge,
+        )];
+        for (name, settings) in &self.index.indices {
+            sections.push((
+                format!("index.indices.{}", name),
+                settings.commit,
+                settings.merge,
+            ));
+        }
+        for (section, commit, merge) in sections {
+            if let Some(CommitPolicy::Docs(0)) | Some(CommitPolicy::IntervalMs(0)) = commit {
+                return Err(invalid(
+                    &format!("{}.commit", section),
+                    "must be greater than 0".to_string(),
+                ));
+            }
+            if let Some(MergePolicyConf::Log(log)) = merge {
+                validate_log_merge(&format!("{}.merge.log", section), &log)?;
+            }
+        }
+        if self.index.base_dir.is_empty() {
+            return Err(invalid("index.base_dir", "must not be empty".to_string()));
+        }
+        exists("log_config", &self.log_config)?;
+        if let Some(ref tls) = self.tls {
+            exists("tls.cert", &tls.cert)?;
+            exists("tls.key", &tls.key)?;
+            if let Some(ref ca) = tls.client_ca {
+                exists("tls.client_ca", ca)?;
+            }
+        }
+        if let Some(HttpConf {
+            tls: Some(ref tls), ..
+        }) = self.http
+        {
+            exists("http.tls.cert", &tls.cert)?;
+            exists("http.tls.key", &tls.key)?;
+            if let Some(ref ca) = tls.client_ca {
+                exists("http.tls.client_ca", ca)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_log_merge(section: &str, log: &LogMergeConf) -> Result<(), ConfigError> {
+    if let Some(factor) = log.merge_factor {
+        if factor < 2 {
+            return Err(invalid(
+                &format!("{}.merge_factor", section),
+                "must be at least 2".to_string(),
+            ));
+        }
+    }
+    if let Some(ratio) = log.deletes_ratio {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(invalid(
+                &format!("{}.deletes_ratio", section),
+                "must be greater than 0 and at most 1".to_string(),
+            ));
+        }
+    }
+    if let (Some(min), Some(max)) = (log.min_segment_docs, log.max_segment_docs) {
+        if min as usize > max {
+            return Err(invalid(
+                &format!("{}.min_segment_docs", section),
+                "must not exceed max_segment_docs".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn invalid(field: &str, msg: String) -> ConfigError {
+    ConfigError::Invalid {
+        field: field.to_string(),
+        msg,
+    }
+}
+
+fn exists(field: &'static str, path: &str) -> Result<(), ConfigError> {
+    if Path::new(path).exists() {
+        Ok(())
+    } else {
+        Err(ConfigError::Missing {
+            field,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[test]
+fn test_load() {
+    let args = Args::parse_from(["tantivy_server", "--bind", "0.0.0.0:9000"]);
+    let config = load(&args).unwrap();
+    assert_eq!(config.bind_addr, "0.0.0.0:9000");
+    assert_eq!(config.index.base_dir, "test_index");
+
+    let args = Args::parse_from(["tantivy_server", "--config", "config/missing.yml"]);
+    let e = load(&args).unwrap_err();
+    assert!(e
+        .to_string()
+        .starts_with("cannot read config file config/missing.yml"));
+
+    let args = Args::parse_from(["tantivy_server", "--log-config", "config/missing.yml"]);
+    assert_eq!(
+        load(&args).unwrap_err().to_string(),
+        "log_config: file config/missing.yml does not exist"
+    );
+
+    let mut config = load(&Args::parse_from(["tantivy_server"])).unwrap();
+    config.byteorder = "middle".to_string();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "byteorder: must be little or big, not \"middle\""
+    );
+
+    let mut config = load(&Args::parse_from(["tantivy_server"])).unwrap();
+    assert_eq!(config.index.commit, CommitPolicy::Immediate);
+    config.index.indices.insert(
+        "logs".to_string(),
+        serde_yaml::from_str("commit: {docs: 0}").unwrap(),
+    );
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "index.indices.logs.commit: must be greater than 0"
+    );
+
+    let mut config = load(&Args::parse_from(["tantivy_server"])).unwrap();
+    config.index.merge =
+        serde_yaml::from_str("log: {merge_factor: 8, deletes_ratio: 1.5}").unwrap();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "index.merge.log.deletes_ratio: must be greater than 0 and at most 1"
+    );
+
+    let mut config = load(&Args::parse_from(["tantivy_server"])).unwrap();
+    config.http = serde_yaml::from_str(
+        "{bind_addr: 127.0.0.1:9200, tls: {cert: config/app.yml, key: config/app.yml, \
+         client_ca: config/missing.pem}}",
+    )
+    .unwrap();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "http.tls.client_ca: file config/missing.pem does not exist"
+    );
+}
@@ -1,22 +1,249 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
 
+use tantivy::query::QueryParserError;
+use tantivy::schema::DocParsingError;
+use tantivy::TantivyError;
+use tantivy_server_protocol::{Error as FrameError, ErrorReply};
+
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+/// Every failure a request can end in. Each variant has a stable numeric
+/// `code` that is sent in the reply, so clients can branch on it.
 #[derive(Debug)]
-pub struct ServerError {
-    msg: String,
+pub enum ServerError {
+    /// The frame or its body could not be decoded, or the command does not
+    /// make sense in the connection's current state.
+    BadRequest(String),
+    FrameTooLarge {
+        len: u32,
+        max: u32,
+    },
+    /// No token, or one that matches no key.
+    Unauthorized(String),
+    /// The key has no grant for the command on that index.
+    Forbidden(String),
+    IndexNotFound(String),
+    SchemaInvalid(String),
+    DocParse {
+        field: Option<String>,
+        msg: String,
+    },
+    /// The index name does not match the allowed pattern, or its directory
+    /// is not under `base_dir`.
+    InvalidIndexName(String),
+    QueryParse {
+        /// Byte offset in the query string, when the parser reports one.
+        position: Option<usize>,
+        msg: String,
+    },
+    WriterLocked(String),
+    /// The writer queue of the index is full.
+    QueueFull(String),
+    /// The segments a `Merge` chose are already being merged by the merge
+    /// policy.
+    MergeInProgress(String),
+    /// The server is already holding `max_connections` connections.
+    TooManyConnections(usize),
+    Io(io::Error),
+    /// Any other tantivy failure.
+    Internal(String),
+}
+
+impl ServerError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ServerError::BadRequest(_) => 100,
+            ServerError::FrameTooLarge { .. } => 101,
+            ServerError::Unauthorized(_) => 110,
+            ServerError::Forbidden(_) => 111,
+            ServerError::IndexNotFound(_) => 200,
+            ServerError::SchemaInvalid(_) => 201,
+            ServerError::DocParse { .. } => 202,
+            ServerError::QueryParse { .. } => 203,
+            ServerError::InvalidIndexName(_) => 204,
+            ServerError::WriterLocked(_) => 300,
+            ServerError::TooManyConnections(_) => 301,
+            ServerError::QueueFull(_) => 302,
+            ServerError::MergeInProgress(_) => 303,
+            ServerError::Io(_) => 500,
+            ServerError::Internal(_) => 501,
+        }
+    }
+
+    /// The status the HTTP front-end answers with.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ServerError::BadRequest(_)
+            | ServerError::SchemaInvalid(_)
+            | ServerError::DocParse { .. }
+            | ServerError::QueryParse { .. }
+            | ServerError::InvalidIndexName(_) => 400,
+            ServerError::FrameTooLarge { .. } => 413,
+            ServerError::Unauthorized(_) => 401,
+            ServerError::Forbidden(_) => 403,
+            ServerError::IndexNotFound(_) => 404,
+            ServerError::WriterLocked(_) | ServerError::MergeInProgress(_) => 409,
+            ServerError::TooManyConnections(_) | ServerError::QueueFull(_) => 503,
+            ServerError::Io(_) | ServerError::Internal(_) => 500,
+        }
+    }
+
+    /// The variant name, sent alongside the code.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerError::BadRequest(_) => "BadRequest",
+            ServerError::FrameTooLarge { .. } => "FrameTooLarge",
+            ServerError::Unauthorized(_) => "Unauthorized",
+            ServerError::Forbidden(_) => "Forbidden",
+            ServerError::IndexNotFound(_) => "IndexNotFound",
+            ServerError::SchemaInvalid(_) => "SchemaInvalid",
+            ServerError::DocParse { .. } => "DocParse",
+            ServerError::QueryParse { .. } => "QueryParse",
+            ServerError::InvalidIndexName(_) => "InvalidIndexName",
+            ServerError::WriterLocked(_) => "WriterLocked",
+            ServerError::TooManyConnections(_) => "TooManyConnections",
+            ServerError::QueueFull(_) => "QueueFull",
+            ServerError::MergeInProgress(_) => "MergeInProgress",
+            ServerError::Io(_) => "Io",
+            ServerError::Internal(_) => "Internal",
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            ServerError::DocParse { field, .. } => field.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            ServerError::QueryParse { position, .. } => *position,
+            _ => None,
+        }
+    }
+}
+
+impl From<&ServerError> for ErrorReply {
+    fn from(e: &ServerError) -> ErrorReply {
+        ErrorReply {
+            kind: e.kind().to_string(),
+            message: e.to_string(),
+            field: e.field().map(str::to_string),
+            position: e.position(),
+        }
+    }
 }
 
 impl fmt::Display for ServerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.msg)
+        match self {
+            ServerError::BadRequest(msg) => write!(f, "BadRequest: {}", msg),
+            ServerError::FrameTooLarge { len, max } => write!(
+                f,
+                "FrameTooLarge: frame of {} bytes exceeds max_frame_bytes {}, use a chunked upload",
+                len, max
+            ),
+            ServerError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            ServerError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            ServerError::IndexNotFound(index) => write!(f, "IndexNotFound: {}", index),
+            ServerError::SchemaInvalid(msg) => write!(f, "SchemaInvalid: {}", msg),
+            ServerError::DocParse {
+                field: Some(field),
+                msg,
+            } => write!(f, "DocParse: field {}: {}", field, msg),
+            ServerError::DocParse { field: None, msg } => write!(f, "DocParse: {}", msg),
+            ServerError::QueryParse {
+                position: Some(position),
+                msg,
+            } => write!(f, "QueryParse: at {}: {}", position, msg),
+            ServerError::QueryParse {
+                position: None,
+                msg,
+            } => write!(f, "QueryParse: {}", msg),
+            ServerError::InvalidIndexName(msg) => write!(f, "InvalidIndexName: {}", msg),
+            ServerError::WriterLocked(msg) => write!(f, "WriterLocked: {}", msg),
+            ServerError::TooManyConnections(max) => {
+                write!(
+                    f,
+                    "TooManyConnections: limit of {} reached, retry later",
+                    max
+                )
+            }
+            ServerError::QueueFull(index) => write!(
+                f,
+                "QueueFull: the writer queue of index {} is full, retry later",
+                index
+            ),
+            ServerError::MergeInProgress(index) => write!(
+                f,
+                "MergeInProgress: segments of index {} are already being merged, retry later",
+                index
+            ),
+            ServerError::Io(e) => write!(f, "Io: {}", e),
+            ServerError::Internal(msg) => write!(f, "Internal: {}", msg),
+        }
     }
 }
 
 impl Error for ServerError {
-    fn description(&self) -> &str {
-        &self.msg
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::Io(e)
     }
 }
 
-// #[derive(Debug)]
-// pub struct ServerError(String);
+impl From<FrameError> for ServerError {
+    fn from(e: FrameError) -> ServerError {
+        match e {
+            FrameError::Io(e) => ServerError::Io(e),
+            FrameError::FrameTooLarge { len, max } => ServerError::FrameTooLarge { len, max },
+            FrameError::Invalid(msg) => ServerError::BadRequest(msg),
+        }
+    }
+}
+
+impl From<TantivyError> for ServerError {
+    fn from(e: TantivyError) -> ServerError {
+        match e {
+            TantivyError::LockFailure(..) => ServerError::WriterLocked(e.to_string()),
+            TantivyError::SchemaError(msg) => ServerError::SchemaInvalid(msg),
+            _ => ServerError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<QueryParserError> for ServerError {
+    fn from(e: QueryParserError) -> ServerError {
+        // tantivy does not say where in the query it failed.
+        ServerError::QueryParse {
+            position: None,
+            msg: e.to_string(),
+        }
+    }
+}
+
+impl From<DocParsingError> for ServerError {
+    fn from(e: DocParsingError) -> ServerError {
+        let field = match &e {
+            DocParsingError::ValueError(field, _) | DocParsingError::NoSuchFieldInSchema(field) => {
+                Some(field.clone())
+            }
+            DocParsingError::NotJson(_) => None,
+        };
+        ServerError::DocParse {
+            field,
+            msg: e.to_string(),
+        }
+    }
+}
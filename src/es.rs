@@ -0,0 +1,587 @@
+//! A subset of the Elasticsearch `_bulk` and `_search` APIs, mapped onto the
+//! native handlers so existing shippers and dashboards can talk to us.
+//!
+//! `_bulk` takes `index`, `create` and `delete` actions; a document's `_id` is
+//! stored in the configured `id_field`, and deletes, replacing `index`
+//! actions and `create` conflict checks match on that field.
+//! `_search` takes `query`, `from` and `size`, where `query` is built from
+//! `match_all`, `match`, `match_phrase`, `term`, `terms`, `range`,
+//! `query_string` and `bool`. Anything else is refused with a 400.
+use crate::auth::{self, ApiKey, Role};
+use crate::{CONF, ENGINE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use tantivy_server::{Batch, Result, ServerError};
+use tantivy_server_protocol::{DocValue, IndexQuery, QueryItem};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EsConf {
+    /// Field that holds the Elasticsearch `_id` of each document.
+    #[serde(default = "default_id_field")]
+    pub id_field: String,
+}
+
+impl Default for EsConf {
+    fn default() -> EsConf {
+        EsConf {
+            id_field: default_id_field(),
+        }
+    }
+}
+
+fn default_id_field() -> String {
+    "id".to_string()
+}
+
+fn id_field() -> &'static str {
+    match CONF.http {
+        Some(ref conf) => &conf.es.id_field,
+        None => "id",
+    }
+}
+
+/// Documents waiting to be written to one index together. Each action
+/// holds the place of its item, filled in once the batch is written, and
+/// whether its document replaces others.
+struct Pending {
+    index: String,
+    batch: Batch,
+    actions: Vec<(usize, String, Option<String>, bool)>,
+}
+
+/// One line of a `_bulk` body, with the document after it for `index` and
+/// `create`.
+struct Action {
+    name: String,
+    index: Option<String>,
+    id: Option<String>,
+    doc: Option<Map<String, Value>>,
+}
+
+/// Reads every action of a `_bulk` body, so that a malformed line fails the
+/// request before any action is run.
+fn parse_bulk(body: &[u8], default_index: Option<&str>) -> Result<Vec<Action>> {
+    let body = std::str::from_utf8(body)
+        .map_err(|e| ServerError::BadRequest(format!("bulk body: {}", e)))?;
+    let mut lines = body.lines().filter(|line| !line.trim().is_empty());
+    let mut actions = Vec::new();
+    while let Some(line) = lines.next() {
+        let (name, meta) = match serde_json::from_str::<Map<String, Value>>(line)
+            .ok()
+            .filter(|action| action.len() == 1)
+            .and_then(|action| action.into_iter().next())
+        {
+            Some((name, Value::Object(meta))) => (name, meta),
+            _ => {
+                return Err(ServerError::BadRequest(format!(
+                    "bulk: malformed action line: {}",
+                    line
+                )))
+            }
+        };
+        let doc = match name.as_str() {
+            "index" | "create" | "update" => {
+                let source = lines.next().ok_or_else(|| {
+                    ServerError::BadRequest(format!("bulk: {} action without a document", name))
+                })?;
+                match serde_json::from_str(source) {
+                    Ok(Value::Object(doc)) => Some(doc),
+                    _ => {
+                        return Err(ServerError::BadRequest(format!(
+                            "bulk: malformed document line: {}",
+                            source
+                        )))
+                    }
+                }
+            }
+            _ => None,
+        };
+        actions.push(Action {
+            index: meta
+                .get("_index")
+                .and_then(Value::as_str)
+                .or(default_index)
+                .map(str::to_string),
+            id: meta.get("_id").map(|id| match id {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            }),
+            name,
+            doc,
+        });
+    }
+    Ok(actions)
+}
+
+/// Runs a `_bulk` NDJSON body. `default_index` comes from the path. Only a
+/// malformed body fails the request; each action that fails is reported in
+/// its item.
+pub fn bulk(key: Option<&ApiKey>, default_index: Option<&str>, body: &[u8]) -> Result<Value> {
+    let started = Instant::now();
+    let actions = parse_bulk(body, default_index)?;
+    let mut items: Vec<Value> = Vec::new();
+    let mut pending: Option<Pending> = None;
+    // Ids added by this request, which the index may not show yet.
+    let mut added: HashSet<(String, String)> = HashSet::new();
+
+    for Action {
+        name,
+        index,
+        id,
+        doc,
+    } in actions
+    {
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let e = ServerError::BadRequest(format!("bulk: {} action without _index", name));
+                items.push(json!({ name: item_err("", &id, &e) }));
+                continue;
+            }
+        };
+        if let Err(e) = auth::authorize(key, Role::Write, &index) {
+            items.push(json!({ name: item_err(&index, &id, &e) }));
+            continue;
+        }
+
+        match (name.as_str(), doc) {
+            ("index", Some(doc)) | ("create", Some(doc)) => {
+                let mut doc: HashMap<String, DocValue> =
+                    match serde_json::from_value(Value::Object(doc)) {
+                        Ok(doc) => doc,
+                        Err(e) => {
+                            let e = ServerError::DocParse {
+                                field: None,
+                                msg: e.to_string(),
+                            };
+                            items.push(json!({ name: item_err(&index, &id, &e) }));
+                            continue;
+                        }
+                    };
+                // A second write to an id waits for the first to be written.
+                let again = matches!(pending, Some(ref p) if p.index == index
+                    && p.actions.iter().any(|(_, _, other, _)| id.is_some() && *other == id));
+                if again || matches!(pending, Some(ref p) if p.index != index) {
+                    flush(pending.take(), &mut items, &mut added);
+                }
+                let seen =
+                    matches!(id, Some(ref id) if added.contains(&(index.clone(), id.clone())));
+                if let Some(ref id) = id {
+                    if name == "create" {
+                        // Refused when the id is taken, by this request too.
+                        let item = QueryItem {
+                            index: index.clone(),
+                            field: id_field().to_string(),
+                            text: id.clone(),
+                        };
+                        match ENGINE.count_term(&item) {
+                            Ok(count) if count > 0 || seen => {
+                                items.push(json!({ name: item_conflict(&index, id) }));
+                                continue;
+                            }
+                            Ok(_) => {}
+                            Err(ref e) => {
+                                items.push(json!({ name: item_err(&index, &Some(id.clone()), e) }));
+                                continue;
+                            }
+                        }
+                    }
+                    doc.entry(id_field().to_string())
+                        .or_insert_with(|| DocValue::from(id.clone()));
+                }
+                let p = match pending {
+                    Some(ref mut p) => p,
+                    None => match ENGINE.batch(&index) {
+                        Ok(batch) => pending.insert(Pending {
+                            index: index.clone(),
+                            batch,
+                            actions: Vec::new(),
+                        }),
+                        Err(ref e) => {
+                            items.push(json!({ name: item_err(&index, &id, e) }));
+                            continue;
+                        }
+                    },
+                };
+                // An `index` action replaces the documents holding its id.
+                let replaces = match id {
+                    Some(ref id) if name == "index" => Some((id_field(), id.as_str())),
+                    _ => None,
+                };
+                match p.batch.push(doc, replaces) {
+                    Ok(replaced) => {
+                        p.actions
+                            .push((items.len(), name, id, replaced > 0 || seen));
+                        items.push(Value::Null);
+                    }
+                    Err(ref e) => items.push(json!({ name: item_err(&index, &id, e) })),
+                }
+            }
+            ("delete", _) => {
+                flush(pending.take(), &mut items, &mut added);
+                let result = match id {
+                    Some(ref id) => ENGINE.delete(QueryItem {
+                        index: index.clone(),
+                        field: id_field().to_string(),
+                        text: id.clone(),
+                    }),
+                    None => Err(ServerError::BadRequest(
+                        "bulk: delete action without _id".to_string(),
+                    )),
+                };
+                let item = match result {
+                    Ok(ref res) if res.deleted > 0 => item_ok(&index, &id, 200, "deleted"),
+                    Ok(_) => item_ok(&index, &id, 404, "not_found"),
+                    Err(ref e) => item_err(&index, &id, e),
+                };
+                if let Some(ref id) = id {
+                    added.remove(&(index, id.clone()));
+                }
+                items.push(json!({ name: item }));
+            }
+            _ => {
+                // `update` needs the stored document, which we do not keep.
+                let e = ServerError::BadRequest(format!("bulk: unsupported action {}", name));
+                items.push(json!({ name: item_err(&index, &id, &e) }));
+            }
+        }
+    }
+    flush(pending.take(), &mut items, &mut added);
+
+    let errors = items
+        .iter()
+        .filter_map(|item| item.as_object()?.values().next())
+        .any(|item| item.get("error").is_some());
+    Ok(json!({
+        "took": started.elapsed().as_millis() as u64,
+        "errors": errors,
+        "items": items,
+    }))
+}
+
+/// Writes a batch of documents, filling in the item of each and noting the
+/// ids it added. Only a failure of the write itself is reported for all of
+/// them.
+fn flush(pending: Option<Pending>, items: &mut [Value], added: &mut HashSet<(String, String)>) {
+    let pending = match pending {
+        Some(pending) if !pending.actions.is_empty() => pending,
+        _ => return,
+    };
+    let result = ENGINE.write_batch(pending.batch);
+    for (slot, name, id, replaces) in pending.actions {
+        let item = match result {
+            Ok(_) if replaces => item_ok(&pending.index, &id, 200, "updated"),
+            Ok(_) => item_ok(&pending.index, &id, 201, "created"),
+            Err(ref e) => item_err(&pending.index, &id, e),
+        };
+        if let (Ok(_), Some(id)) = (&result, id) {
+            added.insert((pending.index.clone(), id));
+        }
+        items[slot] = json!({ name: item });
+    }
+}
+
+fn item_ok(index: &str, id: &Option<String>, status: u16, result: &str) -> Value {
+    json!({ "_index": index, "_id": id, "status": status, "result": result })
+}
+
+fn item_err(index: &str, id: &Option<String>, e: &ServerError) -> Value {
+    json!({ "_index": index, "_id": id, "status": e.http_status(), "error": error_cause(e) })
+}
+
+fn item_conflict(index: &str, id: &str) -> Value {
+    let reason = format!("[{}]: version conflict, document already exists", id);
+    json!({
+        "_index": index,
+        "_id": id,
+        "status": 409,
+        "error": { "type": "version_conflict_engine_exception", "reason": reason },
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchRequest {
+    #[serde(default)]
+    query: Option<Value>,
+    #[serde(default)]
+    from: usize,
+    #[serde(default = "default_size")]
+    size: usize,
+    #[serde(default)]
+    sort: Option<Value>,
+}
+
+fn default_size() -> usize {
+    10
+}
+
+/// Runs a `_search` request body against `index`.
+pub fn search(index: &str, body: &[u8]) -> Result<Value> {
+    let started = Instant::now();
+    let body: &[u8] = if body.is_empty() { b"{}" } else { body };
+    let request: SearchRequest = serde_json::from_slice(body)
+        .map_err(|e| ServerError::BadRequest(format!("search: {}", e)))?;
+    if request.sort.is_some() {
+        return Err(ServerError::BadRequest(
+            "search: sort is not supported, hits are ordered by score".to_string(),
+        ));
+    }
+    let param = match request.query {
+        Some(ref query) => translate(query)?,
+        None => "*".to_string(),
+    };
+    let query = IndexQuery {
+        index: index.to_string(),
+        param,
+        size: request.size,
+        offset: request.from,
+        highlight: false,
+    };
+    let result = ENGINE.search(query)?;
+
+    let hits: Vec<Value> = result
+        .hits
+        .into_iter()
+        .zip(result.scores.iter())
+        .map(|(source, score)| {
+            let id = match source.get(id_field()) {
+                Some(DocValue::Str(s)) => Value::String(s.clone()),
+                Some(DocValue::Null) | None => Value::Null,
+                Some(v) => Value::String(serde_json::to_string(v).unwrap()),
+            };
+            json!({ "_index": index, "_id": id, "_score": score, "_source": source })
+        })
+        .collect();
+    Ok(json!({
+        "took": started.elapsed().as_millis() as u64,
+        "timed_out": false,
+        "_shards": { "total": 1, "successful": 1, "skipped": 0, "failed": 0 },
+        "hits": {
+            "total": { "value": result.total, "relation": "eq" },
+            "max_score": result.scores.first(),
+            "hits": hits,
+        },
+    }))
+}
+
+/// Rewrites a query DSL clause in the tantivy query syntax.
+fn translate(query: &Value) -> Result<String> {
+    let (kind, clause) = match query.as_object().filter(|q| q.len() == 1) {
+        Some(q) => q.iter().next().unwrap(),
+        None => return Err(unsupported(query)),
+    };
+    match kind.as_str() {
+        "match_all" => Ok("*".to_string()),
+        "match" => {
+            let (field, value) = single_field(clause)?;
+            let (text, operator) = match value {
+                Value::Object(opts) => (
+                    opts.get("query").ok_or_else(|| unsupported(query))?,
+                    opts.get("operator").and_then(Value::as_str).unwrap_or("or"),
+                ),
+                text => (text, "or"),
+            };
+            let prefix = if operator.eq_ignore_ascii_case("and") {
+                "+"
+            } else {
+                ""
+            };
+            // Unquoted, so that each term goes through the field's tokenizer.
+            let terms: Vec<String> = literal(text)
+                .split(|c: char| c.is_whitespace() || QUERY_SYNTAX.contains(&c))
+                .filter(|token| !token.is_empty())
+                .map(|token| format!("{}{}:{}", prefix, field, token))
+                .collect();
+            if terms.is_empty() {
+                return Err(unsupported(query));
+            }
+            Ok(format!("({})", terms.join(" ")))
+        }
+        "match_phrase" => {
+            let (field, value) = single_field(clause)?;
+            let text = match value {
+                Value::Object(opts) => opts.get("query").ok_or_else(|| unsupported(query))?,
+                text => text,
+            };
+            Ok(format!("{}:\"{}\"", field, literal(text)))
+        }
+        "term" => {
+            let (field, value) = single_field(clause)?;
+            let value = match value {
+                Value::Object(opts) => opts.get("value").ok_or_else(|| unsupported(query))?,
+                value => value,
+            };
+            Ok(format!("{}:\"{}\"", field, literal(value)))
+        }
+        "terms" => {
+            let (field, values) = single_field(clause)?;
+            let terms: Vec<String> = values
+                .as_array()
+                .ok_or_else(|| unsupported(query))?
+                .iter()
+                .map(|value| format!("{}:\"{}\"", field, literal(value)))
+                .collect();
+            if terms.is_empty() {
+                return Err(unsupported(query));
+            }
+            Ok(format!("({})", terms.join(" ")))
+        }
+        "range" => {
+            let (field, bounds) = single_field(clause)?;
+            let bound = |name: &str| bounds.get(name).map(literal);
+            let (lower_inclusive, lower) = match (bound("gte"), bound("gt")) {
+                (Some(v), _) => (true, v),
+                (None, Some(v)) => (false, v),
+                (None, None) => (true, "*".to_string()),
+            };
+            let (upper_inclusive, upper) = match (bound("lte"), bound("lt")) {
+                (Some(v), _) => (true, v),
+                (None, Some(v)) => (false, v),
+                (None, None) => (true, "*".to_string()),
+            };
+            Ok(format!(
+                "{}:{}{} TO {}{}",
+                field,
+                if lower_inclusive { "[" } else { "{" },
+                lower,
+                upper,
+                if upper_inclusive { "]" } else { "}" },
+            ))
+        }
+        "query_string" => match clause.get("query").and_then(Value::as_str) {
+            Some(q) => Ok(format!("({})", q)),
+            None => Err(unsupported(query)),
+        },
+        "bool" => {
+            let clause = clause.as_object().ok_or_else(|| unsupported(query))?;
+            let mut parts: Vec<String> = Vec::new();
+            for (occur, prefix) in &[
+                ("must", "+"),
+                ("filter", "+"),
+                ("should", ""),
+                ("must_not", "-"),
+            ] {
+                let clauses = match clause.get(*occur) {
+                    Some(Value::Array(clauses)) => clauses.iter().collect(),
+                    Some(q) => vec![q],
+                    None => Vec::new(),
+                };
+                for q in clauses {
+                    parts.push(format!("{}{}", prefix, translate(q)?));
+                }
+            }
+            if parts.is_empty() || parts.iter().all(|p| p.starts_with('-')) {
+                // A query of only exclusions matches nothing.
+                parts.insert(0, "+*".to_string());
+            }
+            Ok(format!("({})", parts.join(" ")))
+        }
+        _ => Err(unsupported(query)),
+    }
+}
+
+/// Splits a `{"field": value}` clause.
+fn single_field(clause: &Value) -> Result<(&String, &Value)> {
+    match clause.as_object().filter(|c| c.len() == 1) {
+        Some(c) => Ok(c.iter().next().unwrap()),
+        None => Err(unsupported(clause)),
+    }
+}
+
+/// Characters the query grammar reads as syntax inside or around a term. It
+/// has no escapes, so `match` text is split on them instead.
+const QUERY_SYNTAX: &[char] = &[
+    '+', '-', '^', '`', ':', '{', '}', '"', '[', ']', '(', ')', '~', '!', '*', '\\', '\'',
+];
+
+/// The text of a value, with the quotes the query syntax cannot escape
+/// removed.
+fn literal(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    text.replace('"', " ")
+}
+
+fn unsupported(query: &Value) -> ServerError {
+    ServerError::BadRequest(format!("search: unsupported query: {}", query))
+}
+
+fn error_cause(e: &ServerError) -> Value {
+    let kind = match e {
+        ServerError::Unauthorized(_) | ServerError::Forbidden(_) => "security_exception",
+        ServerError::IndexNotFound(_) => "index_not_found_exception",
+        ServerError::InvalidIndexName(_) => "invalid_index_name_exception",
+        ServerError::QueryParse { .. } => "query_shard_exception",
+        ServerError::DocParse { .. } => "mapper_parsing_exception",
+        ServerError::FrameTooLarge { .. } => "content_too_long_exception",
+        ServerError::WriterLocked(_) => "version_conflict_engine_exception",
+        ServerError::QueueFull(_) => "es_rejected_execution_exception",
+        ServerError::BadRequest(_) | ServerError::SchemaInvalid(_) => "illegal_argument_exception",
+        _ => "exception",
+    };
+    json!({ "type": kind, "reason": e.to_string() })
+}
+
+/// The Elasticsearch error body for a failed request.
+pub fn error(e: &ServerError) -> Value {
+    json!({ "error": error_cause(e), "status": e.http_status() })
+}
+
+#[test]
+fn test_translate() {
+    let query = json!({"bool": {
+        "must": {"match": {"title": {"query": "rust lang", "operator": "and"}}},
+        "filter": [{"range": {"year": {"gte": 2015, "lt": 2020}}}],
+        "must_not": {"term": {"tag": "draft"}},
+    }});
+    assert_eq!(
+        translate(&query).unwrap(),
+        r#"(+(+title:rust +title:lang) +year:[2015 TO 2020} -tag:"draft")"#
+    );
+    assert_eq!(
+        translate(&json!({"match": {"title": "C++ (2nd ed.) \"rust\" -go a:b"}})).unwrap(),
+        "(title:C title:2nd title:ed. title:rust title:go title:a title:b)"
+    );
+    assert_eq!(
+        translate(&json!({"bool": {"must_not": {"match_all": {}}}})).unwrap(),
+        "(+* -*)"
+    );
+    assert_eq!(
+        translate(&json!({"terms": {"tag": ["a", "b"]}})).unwrap(),
+        r#"(tag:"a" tag:"b")"#
+    );
+    assert!(translate(&json!({"fuzzy": {"title": "rust"}})).is_err());
+}
+
+#[test]
+fn test_parse_bulk() {
+    let body = br#"{"index": {"_index": "book", "_id": 1}}
+{"title": "rust"}
+
+{"delete": {"_id": "2"}}
+{"update": {"_index": "book", "_id": "3"}}
+{"doc": {"title": {"nested": true}}}
+"#;
+    let actions = parse_bulk(body, Some("logs")).unwrap();
+    assert_eq!(actions.len(), 3);
+    assert_eq!(actions[0].id.as_deref(), Some("1"));
+    assert_eq!(actions[0].doc.as_ref().unwrap()["title"], "rust");
+    assert_eq!(actions[1].index.as_deref(), Some("logs"));
+    assert!(actions[1].doc.is_none());
+    assert_eq!(actions[2].name, "update");
+
+    for body in [
+        &br#"{"index": {"_index": "book"}}"#[..],
+        &b"{\"index\": {}}\n[1]\n"[..],
+        &b"{\"index\": {}, \"delete\": {}}\n{}\n"[..],
+        &b"not json\n"[..],
+    ]
+    .iter()
+    {
+        assert!(parse_bulk(body, Some("book")).is_err());
+    }
+}
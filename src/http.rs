@@ -0,0 +1,721 @@
+//! An HTTP/JSON front-end over the same handlers as the TCP protocol.
+//!
+//! | method       | path               | body                          |
+//! | ------------ | ------------------ | ----------------------------- |
+//! | `GET`        | `/_health`         |                               |
+//! | `PUT`        | `/{index}`         | `{"field": [...]}`            |
+//! | `GET`        | `/{index}`         |                               |
+//! | `POST`       | `/{index}/_doc`    | `{"data": [...]}`             |
+//! | `DELETE`     | `/{index}/_doc`    | `{"field": "..", "text": ".."}` |
+//! | `GET`/`POST` | `/{index}/_search` | `{"param": "..", "size": 10, "offset": 0}`, or `?q=&size=&offset=` |
+//!
+//! Replies use the protocol v2 envelope, with the HTTP status following the
+//! error code. When `auth` is configured, requests carry their API key in an
+//! `Authorization: Bearer` header.
+//!
+//! `/_bulk`, `/{index}/_bulk`, and `/{index}/_search` with a query DSL body
+//! speak the Elasticsearch subset in [`crate::es`] instead.
+use crate::auth::{self, ApiKey, Role};
+use crate::es::{self, EsConf};
+use crate::server::envelope;
+use crate::tls::TlsConf;
+use crate::{CONF, ENGINE};
+use log::{error, info};
+use rustls::{ServerConfig, ServerConnection};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tantivy_server::{Result, ServerError};
+use tantivy_server_protocol::{IndexName, Payload, QueryItem};
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct HttpConf {
+    pub bind_addr: String,
+    /// Number of threads serving requests.
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    #[serde(default)]
+    pub es: EsConf,
+    /// Serve HTTPS. With `client_ca`, TLS is terminated by a relay in front
+    /// of the HTTP server, so that client certificates can be checked, and
+    /// the HTTP server answers only the relay.
+    #[serde(default)]
+    pub tls: Option<TlsConf>,
+}
+
+fn default_threads() -> usize {
+    4
+}
+
+/// How often the TLS relay checks for new connections and for `stop`.
+const RELAY_POLL: Duration = Duration::from_millis(50);
+
+/// The running HTTP listener.
+pub struct HttpServer {
+    server: Arc<Server>,
+    threads: Vec<JoinHandle<()>>,
+    /// The TLS relay accepting connections in front of `server`, if any,
+    /// and the flag that stops it.
+    relay: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+/// The TLS relay in front of an HTTP server listening on loopback.
+struct Relay {
+    config: Arc<ServerConfig>,
+    backend: SocketAddr,
+    /// The local addresses of the relay's open connections to `backend`,
+    /// the only ones it answers, so that no other local process gets past
+    /// the client certificate check.
+    peers: Mutex<HashSet<SocketAddr>>,
+    /// Client connections being relayed, each on two threads.
+    open: AtomicUsize,
+    /// Past this, new client connections are closed at once.
+    max: usize,
+    /// A client silent this long is dropped.
+    idle: Duration,
+}
+
+/// Binds the HTTP listener and serves it on background threads.
+pub fn start(conf: &HttpConf) -> Result<HttpServer> {
+    let mut listener = None;
+    let server = match conf.tls {
+        Some(ref tls) if tls.client_ca.is_some() => {
+            // tiny_http cannot check client certificates, so it listens on
+            // loopback behind a relay that can.
+            let tcp = TcpListener::bind(&conf.bind_addr)?;
+            tcp.set_nonblocking(true)?;
+            listener = Some((tcp, tls.server_config()?));
+            Server::http("127.0.0.1:0")
+        }
+        Some(ref tls) => {
+            let (certificate, private_key) = tls.pem()?;
+            Server::https(
+                &conf.bind_addr,
+                SslConfig {
+                    certificate,
+                    private_key,
+                },
+            )
+        }
+        None => Server::http(&conf.bind_addr),
+    }
+    .map_err(|e| ServerError::Io(io::Error::other(e)))?;
+    let server = Arc::new(server);
+    let relay = match listener {
+        Some((listener, config)) => {
+            let backend = server
+                .server_addr()
+                .to_ip()
+                .ok_or_else(|| ServerError::Io(io::Error::other("no loopback address")))?;
+            Some((
+                listener,
+                Arc::new(Relay {
+                    config,
+                    backend,
+                    peers: Mutex::new(HashSet::new()),
+                    open: AtomicUsize::new(0),
+                    max: CONF.max_connections,
+                    idle: Duration::from_secs(CONF.idle_timeout_secs),
+                }),
+            ))
+        }
+        None => None,
+    };
+    let threads = (0..conf.threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let relay = relay.as_ref().map(|(_, relay)| relay.clone());
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = serve(request, relay.as_deref()) {
+                        error!("http respond err={:?}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+    let relay = relay.map(|(listener, relay)| {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let thread = thread::spawn(move || accept_relay(listener, relay, &flag));
+        (stop, thread)
+    });
+    info!("HTTP server started: {}", conf.bind_addr);
+    Ok(HttpServer {
+        server,
+        threads,
+        relay,
+    })
+}
+
+impl HttpServer {
+    /// Stops taking requests; each thread leaves once its current request
+    /// is answered.
+    pub fn stop(&self) {
+        if let Some((ref stop, _)) = self.relay {
+            stop.store(true, Ordering::SeqCst);
+        }
+        for _ in &self.threads {
+            self.server.unblock();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.threads.iter().all(|thread| thread.is_finished())
+            && self
+                .relay
+                .as_ref()
+                .is_none_or(|(_, thread)| thread.is_finished())
+    }
+}
+
+/// Accepts TLS connections until `stop` is set, relaying each to the HTTP
+/// server on a thread of its own, up to `relay.max` at once.
+fn accept_relay(listener: TcpListener, relay: Arc<Relay>, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((sock, _)) => {
+                let admitted = relay
+                    .open
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        if n < relay.max {
+                            Some(n + 1)
+                        } else {
+                            None
+                        }
+                    })
+                    .is_ok();
+                if !admitted {
+                    error!("refusing http tls connection, {} open", relay.max);
+                    continue;
+                }
+                let worker = relay.clone();
+                let spawned =
+                    thread::Builder::new()
+                        .name("http-relay".to_string())
+                        .spawn(move || {
+                            if let Err(e) = worker.serve(sock) {
+                                error!("http tls relay err={:?}", e);
+                            }
+                            worker.open.fetch_sub(1, Ordering::SeqCst);
+                        });
+                if let Err(e) = spawned {
+                    error!("http tls relay thread: {}", e);
+                    relay.open.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(RELAY_POLL),
+            Err(e) => error!("http tls incoming: {}", e),
+        }
+    }
+}
+
+impl Relay {
+    /// Decrypts one client connection into a plain one to `backend`, and
+    /// encrypts what comes back. A client without a valid certificate fails
+    /// the handshake before anything reaches `backend`, and one silent for
+    /// `idle` is dropped.
+    fn serve(&self, sock: TcpStream) -> io::Result<()> {
+        sock.set_nonblocking(false)?;
+        sock.set_read_timeout(Some(self.idle))?;
+        let tls = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        let plain = TcpStream::connect(self.backend)?;
+        let peer = plain.local_addr()?;
+        self.peers.lock().unwrap().insert(peer);
+        let result = relay(sock, &plain, tls);
+        // Forgotten while `plain` still holds the address.
+        self.peers.lock().unwrap().remove(&peer);
+        result
+    }
+
+    /// Whether a request to the HTTP server came through the relay.
+    fn relayed(&self, request: &Request) -> bool {
+        request
+            .remote_addr()
+            .is_some_and(|addr| self.peers.lock().unwrap().contains(addr))
+    }
+}
+
+/// Relays between a client connection and `plain` until both are done.
+fn relay(sock: TcpStream, plain: &TcpStream, tls: ServerConnection) -> io::Result<()> {
+    let tls = Arc::new(Mutex::new(tls));
+    let inbound = {
+        let (sock, plain, tls) = (sock.try_clone()?, plain.try_clone()?, tls.clone());
+        thread::spawn(move || {
+            let result = decrypt(&sock, &plain, &tls);
+            // The backend answers what it got, or gives up on a failure.
+            let how = match result {
+                Ok(()) => Shutdown::Write,
+                Err(_) => Shutdown::Both,
+            };
+            let _ = plain.shutdown(how);
+            result
+        })
+    };
+    let outbound = encrypt(plain, &sock, &tls);
+    let _ = sock.shutdown(Shutdown::Both);
+    let _ = plain.shutdown(Shutdown::Both);
+    let inbound = inbound.join().unwrap_or(Ok(()));
+    outbound.and(inbound)
+}
+
+/// Relays the client's requests to the backend until the client is done.
+fn decrypt(sock: &TcpStream, plain: &TcpStream, tls: &Mutex<ServerConnection>) -> io::Result<()> {
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut data = Vec::new();
+    loop {
+        let n = (&*sock).read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut closed = false;
+        {
+            let mut tls = tls.lock().unwrap();
+            let mut input = &buf[..n];
+            while !input.is_empty() {
+                tls.read_tls(&mut input)?;
+                let state = tls.process_new_packets();
+                // Handshake replies, or the alert that ends a failed one.
+                while tls.wants_write() {
+                    tls.write_tls(&mut &*sock)?;
+                }
+                state.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                match tls.reader().read_to_end(&mut data) {
+                    // The client sent close_notify.
+                    Ok(_) => closed = true,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        // Written without the lock, so replies keep flowing meanwhile.
+        (&*plain).write_all(&data)?;
+        data.clear();
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// Relays the backend's replies to the client until the backend is done.
+fn encrypt(plain: &TcpStream, sock: &TcpStream, tls: &Mutex<ServerConnection>) -> io::Result<()> {
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = (&*plain).read(&mut buf)?;
+        let mut tls = tls.lock().unwrap();
+        if n == 0 {
+            tls.send_close_notify();
+        } else {
+            tls.writer().write_all(&buf[..n])?;
+        }
+        while tls.wants_write() {
+            tls.write_tls(&mut &*sock)?;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn serve(mut request: Request, relay: Option<&Relay>) -> io::Result<()> {
+    if relay.is_some_and(|relay| !relay.relayed(&request)) {
+        // Some other local process, skipping the client certificate check.
+        return request.respond(Response::empty(403));
+    }
+    let started = Instant::now();
+    // A panic fails the request rather than the thread serving it.
+    let (status, body) =
+        match panic::catch_unwind(AssertUnwindSafe(|| answer(&mut request, started))) {
+            Ok(reply) => reply,
+            Err(e) => {
+                let msg = e
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                error!(
+                    "http {} {} panicked: {}",
+                    request.method(),
+                    request.url(),
+                    msg
+                );
+                let e = ServerError::Internal(format!("request handler panicked: {}", msg));
+                (
+                    e.http_status(),
+                    serde_json::to_vec(&envelope(None, started, Err(e))).unwrap(),
+                )
+            }
+        };
+    request.respond(json_response(status, body))
+}
+
+/// The status and body answering a request.
+fn answer(request: &mut Request, started: Instant) -> (u16, Vec<u8>) {
+    let (path, query) = split_url(request.url());
+    if *request.method() == Method::Get && path == "/_health" {
+        return (200, br#"{"status":"Ok"}"#.to_vec());
+    }
+
+    let body = read_body(request);
+    let token = bearer(request);
+    let token = token.as_deref();
+    if let Ok(ref body) = body {
+        if let Some(result) = es_route(request.method(), &path, token, body) {
+            let (status, data) = match result {
+                Ok(data) => (200, data),
+                Err(e) => {
+                    error!("http handle err={:?}", e);
+                    (e.http_status(), es::error(&e))
+                }
+            };
+            return (status, serde_json::to_vec(&data).unwrap());
+        }
+    }
+    let result = match body {
+        Ok(body) => route(request.method(), &path, &query, token, &body),
+        Err(e) => Some(Err(e)),
+    };
+    let result = match result {
+        Some(result) => result,
+        None => {
+            let msg = format!("no route for {} {}", request.method(), path);
+            return (
+                404,
+                serde_json::to_vec(&envelope(None, started, Err(ServerError::BadRequest(msg))))
+                    .unwrap(),
+            );
+        }
+    };
+    if let Err(ref e) = result {
+        error!("http handle err={:?}", e);
+    }
+    let status = match result {
+        Ok(_) => 200,
+        Err(ref e) => e.http_status(),
+    };
+    let envelope = envelope(None, started, result);
+    (status, serde_json::to_vec(&envelope).unwrap())
+}
+
+/// Dispatches a request, or `None` when no endpoint matches.
+fn route(
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    token: Option<&str>,
+    body: &[u8],
+) -> Option<Result<Payload>> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let result = match (method, segments.as_slice()) {
+        (Method::Put, [index]) => access(token, Role::Admin, index)
+            .and_then(|_| with_index(index, body))
+            .and_then(|schema| ENGINE.create(schema))
+            .map(|_| Payload::Created {}),
+        (Method::Get, [index]) => access(token, Role::Read, index)
+            .and_then(|_| {
+                ENGINE.describe(IndexName {
+                    index: index.to_string(),
+                })
+            })
+            .map(Payload::Describe),
+        (Method::Post, [index, "_doc"]) => access(token, Role::Write, index)
+            .and_then(|_| with_index(index, body))
+            .and_then(|data| ENGINE.add(data))
+            .map(Payload::Added),
+        (Method::Post, [index, "_commit"]) => access(token, Role::Write, index)
+            .and_then(|_| {
+                ENGINE.commit(IndexName {
+                    index: index.to_string(),
+                })
+            })
+            .map(|opstamp| Payload::Committed { opstamp }),
+        (Method::Post, [index, "_merge"]) => access(token, Role::Admin, index)
+            .and_then(|_| with_index(index, body))
+            .and_then(|query| ENGINE.merge(query))
+            .map(Payload::Merged),
+        (Method::Delete, [index, "_doc"]) => with_index::<QueryItem>(index, body)
+            .and_then(|item| {
+                // An empty field deletes every document.
+                let role = if item.field.is_empty() {
+                    Role::Admin
+                } else {
+                    Role::Write
+                };
+                access(token, role, index)?;
+                ENGINE.delete(item)
+            })
+            .map(Payload::Deleted),
+        (Method::Get, [index, "_search"]) if body.is_empty() => access(token, Role::Read, index)
+            .and_then(|_| search_params(index, query))
+            .and_then(|query| ENGINE.search(query))
+            .map(Payload::Search),
+        (Method::Get, [index, "_search"]) | (Method::Post, [index, "_search"]) => {
+            access(token, Role::Read, index)
+                .and_then(|_| with_index(index, body))
+                .and_then(|query| ENGINE.search(query))
+                .map(Payload::Search)
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Dispatches the Elasticsearch-compatible endpoints, or `None` for the
+/// native ones.
+fn es_route(
+    method: &Method,
+    path: &str,
+    token: Option<&str>,
+    body: &[u8],
+) -> Option<Result<Value>> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let result = match (method, segments.as_slice()) {
+        // Each action is authorized on its own index.
+        (Method::Post, ["_bulk"]) | (Method::Put, ["_bulk"]) => {
+            authenticate(token).and_then(|key| es::bulk(key, None, body))
+        }
+        (Method::Post, [index, "_bulk"]) | (Method::Put, [index, "_bulk"]) => {
+            authenticate(token).and_then(|key| es::bulk(key, Some(index), body))
+        }
+        (Method::Get, [index, "_search"]) | (Method::Post, [index, "_search"])
+            if is_query_dsl(body) =>
+        {
+            access(token, Role::Read, index).and_then(|_| es::search(index, body))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// A search body is query DSL unless it carries the native `param`.
+fn is_query_dsl(body: &[u8]) -> bool {
+    match serde_json::from_slice::<Map<String, Value>>(body) {
+        Ok(map) => !map.contains_key("param"),
+        Err(_) => false,
+    }
+}
+
+/// The token from an `Authorization: Bearer` header.
+fn bearer(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+fn authenticate(token: Option<&str>) -> Result<Option<&'static ApiKey>> {
+    match token {
+        Some(token) => auth::authenticate(token),
+        None => Ok(None),
+    }
+}
+
+fn access(token: Option<&str>, role: Role, index: &str) -> Result<()> {
+    auth::authorize(authenticate(token)?, role, index)
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>> {
+    let max = CONF.max_frame_bytes;
+    if let Some(len) = request.body_length() {
+        if len > max as usize {
+            return Err(ServerError::FrameTooLarge {
+                len: len as u32,
+                max,
+            });
+        }
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > max as usize {
+        return Err(ServerError::FrameTooLarge {
+            len: body.len() as u32,
+            max,
+        });
+    }
+    Ok(body)
+}
+
+/// Decodes a JSON object body into a command payload, taking the index name
+/// from the path.
+fn with_index<T: DeserializeOwned>(index: &str, body: &[u8]) -> Result<T> {
+    let mut map: Map<String, Value> = if body.is_empty() {
+        Map::new()
+    } else {
+        serde_json::from_slice(body).map_err(|e| ServerError::BadRequest(e.to_string()))?
+    };
+    map.insert("index".to_string(), Value::String(index.to_string()));
+    serde_json::from_value(Value::Object(map)).map_err(|e| ServerError::BadRequest(e.to_string()))
+}
+
+fn search_params<T: DeserializeOwned>(index: &str, query: &HashMap<String, String>) -> Result<T> {
+    let number = |name: &str, default: usize| -> Result<usize> {
+        match query.get(name) {
+            Some(v) => v
+                .parse()
+                .map_err(|_| ServerError::BadRequest(format!("{} must be a number", name))),
+            None => Ok(default),
+        }
+    };
+    let mut map = Map::new();
+    map.insert("index".to_string(), Value::String(index.to_string()));
+    map.insert(
+        "param".to_string(),
+        Value::String(query.get("q").cloned().unwrap_or_default()),
+    );
+    map.insert("size".to_string(), Value::from(number("size", 10)?));
+    map.insert("offset".to_string(), Value::from(number("offset", 0)?));
+    serde_json::from_value(Value::Object(map)).map_err(|e| ServerError::BadRequest(e.to_string()))
+}
+
+fn json_response(status: u16, data: Vec<u8>) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_data(data)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+/// Splits a request URL into its path and decoded query parameters.
+fn split_url(url: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url, ""),
+    };
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (decode(&pair[..i]), decode(&pair[i + 1..])),
+            None => (decode(pair), String::new()),
+        })
+        .collect();
+    (decode(path), params)
+}
+
+/// Percent-decodes a URL component, reading `+` as a space.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[test]
+fn test_split_url() {
+    let (path, params) = split_url("/book/_search?q=title%3A%22rust+lang%22&size=5");
+    assert_eq!(path, "/book/_search");
+    assert_eq!(params["q"], "title:\"rust lang\"");
+    assert_eq!(params["size"], "5");
+}
+
+#[test]
+fn test_tls_relay() {
+    use crate::tls::TestCerts;
+    use std::fs;
+    use std::sync::mpsc;
+
+    let dir = std::env::temp_dir().join(format!("tantivy-server-relay-{}", std::process::id()));
+    let certs = TestCerts::new(&dir);
+
+    // An echo server stands in for tiny_http, and tells whether each
+    // connection is one the relay opened.
+    let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = echo.local_addr().unwrap();
+    let (relay_tx, relay_rx) = mpsc::channel::<Arc<Relay>>();
+    let (seen_tx, seen) = mpsc::channel();
+    thread::spawn(move || {
+        let relay = relay_rx.recv().unwrap();
+        for stream in echo.incoming() {
+            let mut stream = stream.unwrap();
+            // Checked once data arrives, as tiny_http reads a request first.
+            let mut first = [0u8; 1];
+            if stream.read_exact(&mut first).is_err() {
+                continue;
+            }
+            let peer = stream.peer_addr().unwrap();
+            let _ = seen_tx.send(relay.peers.lock().unwrap().contains(&peer));
+            let _ = stream.write_all(&first);
+            let _ = io::copy(&mut stream.try_clone().unwrap(), &mut stream);
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let relay = Arc::new(Relay {
+        config: certs.conf.server_config().unwrap(),
+        backend,
+        peers: Mutex::new(HashSet::new()),
+        open: AtomicUsize::new(0),
+        max: 1,
+        idle: Duration::from_secs(5),
+    });
+    relay_tx.send(relay.clone()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let accepting = {
+        let (relay, stop) = (relay.clone(), stop.clone());
+        thread::spawn(move || accept_relay(listener, relay, &stop))
+    };
+
+    let connect = |with_cert: bool| -> io::Result<Vec<u8>> {
+        let mut stream = certs.connect(addr, with_cert)?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let mut buf = vec![0u8; 18];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let wait_open = |n: usize| {
+        while relay.open.load(Ordering::SeqCst) != n {
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    assert_eq!(connect(true).unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+    assert!(seen.recv().unwrap());
+    assert!(connect(false).is_err());
+    // Straight to the backend, not through the relay.
+    let mut direct = TcpStream::connect(backend).unwrap();
+    direct.write_all(b"G").unwrap();
+    assert!(!seen.recv().unwrap());
+    drop(direct);
+
+    // A connection over the limit is closed at once.
+    wait_open(0);
+    let held = TcpStream::connect(addr).unwrap();
+    wait_open(1);
+    assert!(connect(true).is_err());
+    drop(held);
+    wait_open(0);
+    assert_eq!(connect(true).unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+
+    stop.store(true, Ordering::SeqCst);
+    accepting.join().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,70 +1,167 @@
-use crate::CONF;
-use log::info;
-use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
-use std::io::{Error, ErrorKind, Result};
-use tantivy::merge_policy::NoMergePolicy;
-
-use super::jieba_tokenizer;
-use super::{get_index, get_index_writer};
-
-#[derive(Deserialize, Serialize, Debug)]
-pub struct IndexData {
+use crate::error::{Result, ServerError};
+use log::error;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tantivy::collector::Count;
+use tantivy::query::TermQuery;
+use tantivy::schema::{Field, IndexRecordOption, Schema};
+use tantivy::{Document, Term};
+use tantivy_server_protocol::{AddResult, DocValue, IndexData};
+
+use super::doc::to_document;
+use super::registry::IndexHandle;
+use super::writer::{Op, Queue};
+use super::Engine;
+
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(1);
+
+/// A chunked upload: each chunk of documents goes to the writer as it
+/// arrives, and they are committed together once the upload ends. Other
+/// writes to the index fail until then, and dropping the upload unfinished
+/// discards its documents.
+pub struct Upload {
+    // Keeps the index open while the upload lasts.
+    _handle: Arc<IndexHandle>,
+    schema: Schema,
+    queue: Queue,
+    id: u64,
+    docs: usize,
+    finished: bool,
+}
+
+/// Documents written to an index at once. Each is checked against the
+/// schema as it is pushed, so one that does not match is refused alone, and
+/// the documents they replace are deleted in the same write, so a write
+/// that fails deletes nothing.
+pub struct Batch {
+    handle: Arc<IndexHandle>,
     index: String,
-    data: Vec<Map<String, Value>>,
+    deletes: Vec<(Field, String)>,
+    docs: Vec<Document>,
 }
 
-pub fn add_index(index_json: &str) -> Result<()> {
-    let json_index = serde_json::from_str::<IndexData>(index_json)?;
+impl Engine {
+    /// An empty batch of writes to `index`.
+    pub fn batch(&self, index: &str) -> Result<Batch> {
+        Ok(Batch {
+            handle: self.handle(index)?,
+            index: index.to_string(),
+            deletes: Vec::new(),
+            docs: Vec::new(),
+        })
+    }
 
-    let index = get_index(json_index.index)?;
+    /// Deletes what the batch replaces and adds its documents in one write,
+    /// committed as the commit policy says.
+    pub fn write_batch(&self, batch: Batch) -> Result<AddResult> {
+        let count = batch.docs.len();
+        let opstamp = self.write(
+            &batch.handle,
+            &batch.index,
+            Op::Replace(batch.deletes, batch.docs),
+        )?;
+        Ok(AddResult {
+            opstamp,
+            docs: count,
+        })
+    }
 
-    index
-        .tokenizers()
-        .register("jieba", jieba_tokenizer::JiebaTokenizer {});
+    /// Adds the documents and commits them, after any other write queued on
+    /// the index.
+    pub fn add(&self, json_index: IndexData) -> Result<AddResult> {
+        let handle = self.handle(&json_index.index)?;
+        let docs = parse(&handle.index.schema(), json_index.data)?;
+        let count = docs.len();
+        let opstamp = self.write(&handle, &json_index.index, Op::Add(docs))?;
+        Ok(AddResult {
+            opstamp,
+            docs: count,
+        })
+    }
 
-    let schema = index.schema();
+    pub fn begin_upload(&self, index: String) -> Result<Upload> {
+        let handle = self.handle(&index)?;
+        let queue = self.queue(&handle, &index)?;
+        let id = NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed);
+        queue.submit(Op::BeginUpload(id))?;
+        Ok(Upload {
+            schema: handle.index.schema(),
+            _handle: handle,
+            queue,
+            id,
+            docs: 0,
+            finished: false,
+        })
+    }
+}
 
-    let schema_clone = schema.clone();
+impl Batch {
+    /// Holds a document for the write. With `replaces`, a field and its
+    /// text, the documents holding that text are deleted before it is
+    /// added; returns how many the index holds now.
+    pub fn push(
+        &mut self,
+        doc: HashMap<String, DocValue>,
+        replaces: Option<(&str, &str)>,
+    ) -> Result<u64> {
+        let schema = self.handle.index.schema();
+        let doc = to_document(&schema, doc)?;
+        let replaced = match replaces {
+            Some((field, text)) => {
+                let field = schema.get_field(field).ok_or_else(|| {
+                    ServerError::BadRequest(format!("field {} does not exist", field))
+                })?;
+                let query =
+                    TermQuery::new(Term::from_field_text(field, text), IndexRecordOption::Basic);
+                let count = self.handle.reader.searcher().search(&query, &Count)? as u64;
+                self.deletes.push((field, text.to_string()));
+                count
+            }
+            None => 0,
+        };
+        self.docs.push(doc);
+        Ok(replaced)
+    }
 
-    let mut index_writer = get_index_writer(&index)?;
-    if CONF.index.is_merge {
-        index_writer.set_merge_policy(Box::new(NoMergePolicy));
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
     }
-    for m in json_index.data {
-        let data = serde_json::to_string(&m)?;
-        match schema_clone.parse_document(&data) {
-            Ok(doc) => {
-                index_writer.add_document(doc);
-            }
-            Err(e) => {
-                // index_writer.rollback();
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("DocParsingError: {}", e),
-                ));
-            }
-        }
+}
+
+impl Upload {
+    /// Adds a chunk of documents, returning how many the upload holds so far.
+    pub fn add(&mut self, data: Vec<HashMap<String, DocValue>>) -> Result<usize> {
+        let docs = parse(&self.schema, data)?;
+        let count = docs.len();
+        self.queue.submit_wait(Op::UploadChunk(self.id, docs))?;
+        self.docs += count;
+        Ok(self.docs)
     }
-    let index_result = index_writer.commit();
-
-    match index_result {
-        Ok(docstamp) => {
-            info!("Commit succeed, docstamp at {}", docstamp);
-            // info!("Waiting for merging threads");
-            index_writer.wait_merging_threads().map_err(|e| {
-                Error::new(ErrorKind::Other, format!("wait_merging_threads: {}", e))
-            })?;
-        }
-        Err(e) => {
-            index_writer.rollback().unwrap();
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("add_index index_writer rollback: {}", e),
-            ));
+
+    pub fn commit(mut self) -> Result<AddResult> {
+        // The writer task ends the upload whether or not the commit succeeds.
+        self.finished = true;
+        let opstamp = self.queue.submit_wait(Op::EndUpload(self.id))?;
+        Ok(AddResult {
+            opstamp,
+            docs: self.docs,
+        })
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.queue.submit_wait(Op::AbortUpload(self.id)) {
+                error!("rollback err={:?}", e);
+            }
         }
     }
-    Ok(())
+}
+
+fn parse(schema: &Schema, data: Vec<HashMap<String, DocValue>>) -> Result<Vec<Document>> {
+    data.into_iter().map(|m| to_document(schema, m)).collect()
 }
 
 #[test]
@@ -84,14 +181,11 @@ fn test_add_index() {
     let s = fs::read_to_string(std::path::PathBuf::from("test_index/wikipedia.json")).unwrap();
 
     let data_json = IndexData {
-        index: "test_index/wikipedia".to_string(),
-        data: serde_json::from_str::<Vec<Map<String, Value>>>(&s).unwrap(),
+        index: "wikipedia".to_string(),
+        data: serde_json::from_str::<Vec<HashMap<String, DocValue>>>(&s).unwrap(),
     };
 
-    println!(
-        "{:?}",
-        add_index(&serde_json::to_string(&data_json).unwrap())
-    );
+    println!("{:?}", super::test_engine().add(data_json));
 
     // let path = PathBuf::from("test_index/wikipedia");
     // let index = Index::open_in_dir(&path).unwrap();
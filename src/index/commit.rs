@@ -0,0 +1,15 @@
+use crate::error::Result;
+use tantivy_server_protocol::IndexName;
+
+use super::writer::Op;
+use super::Engine;
+
+impl Engine {
+    /// Commits the adds and deletes the index's commit policy is holding
+    /// back, after any other write queued on the index, and returns the
+    /// commit's opstamp.
+    pub fn commit(&self, item: IndexName) -> Result<u64> {
+        let handle = self.handle(&item.index)?;
+        self.write(&handle, &item.index, Op::Commit)
+    }
+}
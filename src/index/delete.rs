@@ -1,54 +1,56 @@
-use std::io::{Error, ErrorKind, Result};
+use crate::error::{Result, ServerError};
 
-use serde::{Deserialize, Serialize};
+use tantivy::collector::Count;
+use tantivy::query::TermQuery;
+use tantivy::schema::IndexRecordOption;
 use tantivy::Term;
+use tantivy_server_protocol::{DeleteResult, QueryItem};
 
-use super::{get_index, get_index_writer};
-
-#[derive(Deserialize, Serialize, Debug)]
-struct QueryItem {
-    index: String,
-    field: String,
-    text: String,
-}
+use super::writer::Op;
+use super::Engine;
 
 // todo: get_index函数需要包装SchemaBuilder
 
-pub fn delete_index(query: &str) -> Result<()> {
-    let item = serde_json::from_str::<QueryItem>(query)?;
-    if item.index == "" {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "delete_index: index could not be empty!",
-        ));
-    }
-    let index = get_index(item.index)?;
+impl Engine {
+    /// Deletes the documents whose `field` holds the term `text`, or every
+    /// document when `field` is empty.
+    pub fn delete(&self, item: QueryItem) -> Result<DeleteResult> {
+        if item.index == "" {
+            return Err(ServerError::BadRequest(
+                "delete_index: index could not be empty!".to_string(),
+            ));
+        }
+        let handle = self.handle(&item.index)?;
 
-    let mut index_writer = get_index_writer(&index)?;
-    if item.field != "" {
-        if item.text == "" {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!(
+        let searcher = handle.reader.searcher();
+        let deleted;
+        let term;
+        if item.field != "" {
+            if item.text == "" {
+                return Err(ServerError::BadRequest(format!(
                     "delete_index: field {} text could not be empty!",
                     item.field
-                ),
-            ));
-        }
+                )));
+            }
 
-        if let Some(f) = index.schema().get_field(&item.field) {
-            index_writer.delete_term(Term::from_field_text(f, &item.text));
+            if let Some(f) = handle.index.schema().get_field(&item.field) {
+                let t = Term::from_field_text(f, &item.text);
+                deleted =
+                    searcher.search(&TermQuery::new(t, IndexRecordOption::Basic), &Count)? as u64;
+                term = Some((f, item.text.clone()));
+            } else {
+                return Err(ServerError::BadRequest(format!(
+                    "delete_index: field {} not exist!",
+                    item.field
+                )));
+            }
         } else {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("delete_index: field {} not exist!", item.field),
-            ));
+            deleted = searcher.num_docs();
+            term = None;
         }
-    } else {
-        index_writer.delete_all_documents().unwrap();
+        let opstamp = self.write(&handle, &item.index, Op::Delete(term))?;
+        Ok(DeleteResult { opstamp, deleted })
     }
-    index_writer.commit().unwrap();
-    Ok(())
 }
 
 #[test]
@@ -56,7 +58,7 @@ fn test_delete_index() {
     // let query = "{\"index\":\"wikipedia\",\"param\":\"title:\\\"Vado\\\" AND (url:\\\"https://en.wikipedia.org/wiki?curid=48693283\\\" OR body:\\\"Vado\\\")\",\"size\":20,\"offset\":0}";
     let query = "{\"index\":\"book\",\"field\":\"BookId\",\"text\":\"l1\"}";
 
-    match delete_index(query) {
+    match super::test_engine().delete(serde_json::from_str::<QueryItem>(query).unwrap()) {
         Ok(res) => {
             println!("{:#?}", res);
         }
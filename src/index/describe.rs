@@ -0,0 +1,41 @@
+use crate::error::Result;
+
+use tantivy_server_protocol::{IndexInfo, IndexName, SegmentInfo};
+
+use super::Engine;
+
+impl Engine {
+    pub fn describe(&self, item: IndexName) -> Result<IndexInfo> {
+        let metas = self.handle(&item.index)?.index.load_metas()?;
+        let segments: Vec<SegmentInfo> = metas
+            .segments
+            .iter()
+            .map(|segment| SegmentInfo {
+                id: segment.id().uuid_string(),
+                num_docs: segment.num_docs(),
+                num_deleted_docs: segment.num_deleted_docs(),
+            })
+            .collect();
+        Ok(IndexInfo {
+            index: item.index,
+            schema: serde_json::to_value(metas.schema).unwrap(),
+            opstamp: metas.opstamp,
+            num_docs: segments.iter().map(|s| s.num_docs as u64).sum(),
+            segments,
+        })
+    }
+}
+
+#[test]
+fn test_describe_index() {
+    match super::test_engine().describe(IndexName {
+        index: "book".to_string(),
+    }) {
+        Ok(res) => {
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        }
+        Err(e) => {
+            println!("{}", e);
+        }
+    }
+}
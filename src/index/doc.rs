@@ -0,0 +1,45 @@
+use crate::error::Result;
+
+use std::collections::HashMap;
+use tantivy::schema::{DocParsingError, FieldType, FieldValue, Schema, Value};
+use tantivy::Document;
+use tantivy_server_protocol::DocValue;
+
+/// A stored value as it goes out in a search hit.
+pub fn doc_value(value: &Value) -> DocValue {
+    match value {
+        Value::Str(s) => DocValue::Str(s.clone()),
+        Value::U64(n) => DocValue::U64(*n),
+        Value::I64(n) => DocValue::I64(*n),
+        Value::F64(n) => DocValue::F64(*n),
+        Value::Bytes(b) => DocValue::Bytes(b.clone()),
+        // Dates, facets and pre-tokenized text keep their JSON form.
+        v => serde_json::from_value(serde_json::to_value(v).unwrap()).unwrap(),
+    }
+}
+
+/// Builds a tantivy document the way `Schema::parse_document` does, except
+/// that bytes fields also take raw bytes.
+pub fn to_document(schema: &Schema, doc: HashMap<String, DocValue>) -> Result<Document> {
+    let mut document = Document::default();
+    for (name, value) in doc {
+        let field = schema
+            .get_field(&name)
+            .ok_or_else(|| DocParsingError::NoSuchFieldInSchema(name.clone()))?;
+        let field_type = schema.get_field_entry(field).field_type();
+        let values = match value {
+            DocValue::Array(items) => items,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match (value, field_type) {
+                (DocValue::Bytes(bytes), FieldType::Bytes(_)) => Value::Bytes(bytes),
+                (value, _) => field_type
+                    .value_from_json(&value.to_json())
+                    .map_err(|e| DocParsingError::ValueError(name.clone(), e))?,
+            };
+            document.add(FieldValue::new(field, value));
+        }
+    }
+    Ok(document)
+}
@@ -3,38 +3,71 @@
 //! It implements a [`JiebaTokenizer`](./struct.JiebaTokenizer.html) for the purpose.
 #![forbid(unsafe_code)]
 
-use crate::{JIEBA, STOP_WORD};
-use jieba_rs;
+use jieba_rs::Jieba;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
 
 use tantivy::tokenizer::{BoxTokenStream, Token, TokenStream, Tokenizer};
 
+use super::JiebaConf;
+
 /// Tokenize the text using jieba_rs.
 ///
-/// Need to load dict on first tokenization.
+/// The dictionary and stop words are loaded by [`JiebaTokenizer::load`] and
+/// shared by its clones.
 ///
 /// # Example
-/// ```rust
+/// ```ignore
 /// use tantivy::tokenizer::*;
-/// let tokenizer = tantivy_jieba::JiebaTokenizer {};
+/// let tokenizer = JiebaTokenizer::load(&conf);
 /// let mut token_stream = tokenizer.token_stream("测试");
 /// assert_eq!(token_stream.next().unwrap().text, "测试");
 /// assert!(token_stream.next().is_none());
 /// ```
 ///
 /// # Register tantivy tokenizer
-/// ```rust
+/// ```ignore
 /// use tantivy::schema::Schema;
 /// use tantivy::tokenizer::*;
 /// use tantivy::Index;
 /// # fn main() {
 /// # let schema = Schema::builder().build();
-/// let tokenizer = tantivy_jieba::JiebaTokenizer {};
+/// let tokenizer = JiebaTokenizer::load(&conf);
 /// let index = Index::create_in_ram(schema);
 /// index.tokenizers()
 ///      .register("jieba", tokenizer);
 /// # }
 #[derive(Clone)]
-pub struct JiebaTokenizer;
+pub struct JiebaTokenizer {
+    jieba: Arc<Jieba>,
+    stop_words: Arc<HashSet<String>>,
+}
+
+impl JiebaTokenizer {
+    /// Falls back to jieba's built-in dictionary and no stop words when the
+    /// configured files cannot be opened.
+    pub fn load(conf: &JiebaConf) -> JiebaTokenizer {
+        let jieba = match File::open(&conf.dict_path) {
+            Ok(r) => Jieba::with_dict(&mut BufReader::new(r)).unwrap(),
+            Err(_) => Jieba::new(),
+        };
+        let mut stop_words = HashSet::new();
+        if let Ok(r) = File::open(&conf.stop_word_path) {
+            for line in BufReader::new(r).lines().map_while(Result::ok) {
+                let word = line.trim();
+                if !word.is_empty() {
+                    stop_words.insert(word.to_string());
+                }
+            }
+        }
+        JiebaTokenizer {
+            jieba: Arc::new(jieba),
+            stop_words: Arc::new(stop_words),
+        }
+    }
+}
 
 /// Token stream instantiated by [`JiebaTokenizer`](./struct.JiebaTokenizer.html).
 ///
@@ -67,12 +100,14 @@ impl Tokenizer for JiebaTokenizer {
     fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
         let mut indices = text.char_indices().collect::<Vec<_>>();
         indices.push((text.len(), '\0'));
-        let orig_tokens = JIEBA.tokenize(text, jieba_rs::TokenizeMode::Search, true);
+        let orig_tokens = self
+            .jieba
+            .tokenize(text, jieba_rs::TokenizeMode::Search, true);
         let mut tokens = Vec::new();
 
         for i in 0..orig_tokens.len() {
             let token = &orig_tokens[i];
-            if STOP_WORD.contains(&token.word.to_string()) {
+            if self.stop_words.contains(token.word) {
                 continue;
             }
             tokens.push(Token {
@@ -93,7 +128,8 @@ mod tests {
     #[test]
     fn it_works() {
         use tantivy::tokenizer::*;
-        let tokenizer = crate::index::jieba_tokenizer::JiebaTokenizer {};
+        let conf = crate::index::test_engine().conf().tokenizer.jieba.clone();
+        let tokenizer = super::JiebaTokenizer::load(&conf);
         let mut token_stream = tokenizer.token_stream(
             "张华考上了北京大学；李萍进了中等技术学校；我在百货公司当售货员：我们都有光明的前途",
         );
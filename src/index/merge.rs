@@ -0,0 +1,25 @@
+use crate::error::{Result, ServerError};
+use tantivy_server_protocol::{MergeQuery, MergeResult};
+
+use super::writer::Op;
+use super::Engine;
+
+impl Engine {
+    /// Merges the index's smallest segments until at most
+    /// `query.max_segments` are left, after any other write queued on the
+    /// index, then removes the files no longer used. Blocks the index's
+    /// writes until it is done.
+    pub fn merge(&self, query: MergeQuery) -> Result<MergeResult> {
+        if query.max_segments == 0 {
+            return Err(ServerError::BadRequest(
+                "merge: max_segments must be greater than 0".to_string(),
+            ));
+        }
+        let handle = self.handle(&query.index)?;
+        let opstamp = self.write(&handle, &query.index, Op::Merge(query.max_segments))?;
+        Ok(MergeResult {
+            opstamp,
+            segments: handle.index.searchable_segment_ids()?.len(),
+        })
+    }
+}
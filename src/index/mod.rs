@@ -1,53 +1,285 @@
-use crate::CONF;
-use std::{
-    io::{Error, ErrorKind, Result},
-    path::Path,
-};
+use crate::error::{Result, ServerError};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use tantivy::{Index, IndexWriter};
-pub(crate) mod add;
-pub(crate) mod create;
-pub(crate) mod delete;
+mod add;
+mod commit;
+mod create;
+mod delete;
+mod describe;
+mod doc;
 mod jieba_tokenizer;
-pub(crate) mod search;
+mod merge;
+mod registry;
+mod search;
+mod stats;
+mod wal;
+mod writer;
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub use add::{Batch, Upload};
+use jieba_tokenizer::JiebaTokenizer;
+use registry::Registry;
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
 pub struct IndexConf {
-    base_dir: String,
-    is_merge: bool,
-    thread_num: usize,
-    total_heap_size: usize, // size in mb
+    /// Directory holding one subdirectory per index.
+    pub base_dir: String,
+    /// Whether segments are merged in the background with tantivy's
+    /// default log merge policy. `merge` replaces it.
+    pub is_merge: bool,
+    pub thread_num: usize,
+    pub total_heap_size: usize, // size in mb
+    /// Upper bound on `size` in a search request.
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: usize,
+    /// Upper bound on `offset` in a search request. Collecting a page costs
+    /// memory for every hit before it too.
+    #[serde(default = "default_max_offset")]
+    pub max_offset: usize,
+    /// Seconds an index may go unused before its reader and writer are
+    /// closed.
+    #[serde(default = "default_idle_close_secs")]
+    pub idle_close_secs: u64,
+    /// Mutations an index's writer may have waiting before more are refused
+    /// with `QueueFull`.
+    #[serde(default = "default_writer_queue_size")]
+    pub writer_queue_size: usize,
+    /// Seconds a chunked upload may go without a chunk before it is rolled
+    /// back, so that a stalled client does not keep the index's other
+    /// writes failing with `WriterLocked`.
+    #[serde(default = "default_upload_idle_secs")]
+    pub upload_idle_secs: u64,
+    /// When an index's writer commits, unless `indices` sets it for that
+    /// index.
+    #[serde(default)]
+    pub commit: CommitPolicy,
+    /// How segments are merged in the background, unless `indices` sets it
+    /// for that index.
+    #[serde(default)]
+    pub merge: Option<MergePolicyConf>,
+    /// Settings for single indices, by name.
+    #[serde(default)]
+    pub indices: HashMap<String, IndexSettings>,
     pub tokenizer: TokenizerConf,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
+fn default_max_page_size() -> usize {
+    120
+}
+
+fn default_max_offset() -> usize {
+    10000
+}
+
+fn default_idle_close_secs() -> u64 {
+    300
+}
+
+fn default_writer_queue_size() -> usize {
+    64
+}
+
+fn default_upload_idle_secs() -> u64 {
+    30
+}
+
+/// How long adds and deletes may wait before they are committed and
+/// searchable. A `Commit` command commits at once under any policy.
+#[derive(Deserialize, Serialize, Copy, Clone, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitPolicy {
+    /// After every add or delete.
+    #[default]
+    Immediate,
+    /// Once this many documents were added, or deletes applied, since the
+    /// last commit. A delete counts once, however many documents it
+    /// matches.
+    Docs(usize),
+    /// This many milliseconds after the first add or delete since the last
+    /// commit.
+    IntervalMs(u64),
+}
+
+/// How the writer merges segments in the background.
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicyConf {
+    /// Segments are only merged by a `Merge` command.
+    None,
+    Log(LogMergeConf),
+}
+
+/// Tantivy's log merge policy, which merges segments of similar sizes.
+/// Unset fields keep tantivy's defaults.
+#[derive(Deserialize, Serialize, Copy, Clone, Default, PartialEq, Debug)]
+pub struct LogMergeConf {
+    /// Segments with fewer documents are all merged as if they had this
+    /// many.
+    #[serde(default)]
+    pub min_segment_docs: Option<u32>,
+    /// Segments with more documents are left out of merges.
+    #[serde(default)]
+    pub max_segment_docs: Option<usize>,
+    /// How many segments of a similar size it takes to merge them.
+    #[serde(default)]
+    pub merge_factor: Option<usize>,
+    /// A segment whose deleted documents reach this share of it is merged,
+    /// whatever its size. Between 0 and 1.
+    #[serde(default)]
+    pub deletes_ratio: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Debug)]
+pub struct IndexSettings {
+    #[serde(default)]
+    pub commit: Option<CommitPolicy>,
+    #[serde(default)]
+    pub merge: Option<MergePolicyConf>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
 pub struct TokenizerConf {
-    pub jieba: Tokenizer,
+    pub jieba: JiebaConf,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct Tokenizer {
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct JiebaConf {
+    /// A jieba dictionary replacing the built-in one, if the file exists.
     pub dict_path: String,
+    /// Words left out of the jieba token stream, one per line, if the file
+    /// exists.
     pub stop_word_path: String,
 }
 
-fn get_index(index: String) -> Result<Index> {
-    Index::open_in_dir(Path::new(&CONF.index.base_dir).join(index))
-        .map_err(|e| Error::new(ErrorKind::Other, format!("Index open_in_dir: {}", e)))
-}
-
-fn get_index_writer(index: &Index) -> Result<IndexWriter> {
-    index
-        // .writer_with_num_threads(
-        //     CONF.index.thread_num,
-        //     CONF.index.total_heap_size * 1024 * 1024,
-        // )
-        .writer(CONF.index.total_heap_size * 1024 * 1024)
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Index writer_with_num_threads: {}", e),
-            )
-        })
+/// Creates, fills and searches the indices under one `base_dir`. The jieba
+/// dictionary and stop words are loaded once, when the engine is built, and
+/// each index stays open between requests, sharing one reader and one
+/// writer task that applies its mutations in order.
+pub struct Engine {
+    conf: IndexConf,
+    jieba: JiebaTokenizer,
+    registry: Registry,
+}
+
+lazy_static! {
+    static ref INDEX_NAME: Regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]{0,127}$").unwrap();
+}
+
+fn is_valid_index_name(index: &str) -> bool {
+    INDEX_NAME.is_match(index)
+}
+
+impl Engine {
+    pub fn new(conf: IndexConf) -> Engine {
+        let jieba = JiebaTokenizer::load(&conf.tokenizer.jieba);
+        let registry = Registry::new(Duration::from_secs(conf.idle_close_secs));
+        let engine = Engine {
+            conf,
+            jieba,
+            registry,
+        };
+        engine.recover();
+        engine
+    }
+
+    pub fn conf(&self) -> &IndexConf {
+        &self.conf
+    }
+
+    fn merge_policy(&self, index: &str) -> MergePolicyConf {
+        let merge = self
+            .conf
+            .indices
+            .get(index)
+            .and_then(|settings| settings.merge)
+            .or(self.conf.merge);
+        match merge {
+            Some(merge) => merge,
+            None if self.conf.is_merge => MergePolicyConf::Log(LogMergeConf::default()),
+            None => MergePolicyConf::None,
+        }
+    }
+
+    fn commit_policy(&self, index: &str) -> CommitPolicy {
+        self.conf
+            .indices
+            .get(index)
+            .and_then(|settings| settings.commit)
+            .unwrap_or(self.conf.commit)
+    }
+
+    /// Resolves an index name to its directory, which must lie under
+    /// `base_dir`.
+    fn index_dir(&self, index: &str) -> Result<PathBuf> {
+        if !is_valid_index_name(index) {
+            return Err(ServerError::InvalidIndexName(format!(
+                "{:?} must match the pattern [a-zA-Z0-9][a-zA-Z0-9_.-]*, up to 128 characters",
+                index
+            )));
+        }
+        let base = fs::canonicalize(&self.conf.base_dir).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => ServerError::IndexNotFound(index.to_string()),
+            _ => e.into(),
+        })?;
+        let dir = base.join(index);
+        // The name cannot climb out, but a symlink inside base_dir could.
+        match fs::canonicalize(&dir) {
+            Ok(real) if !real.starts_with(&base) => Err(ServerError::InvalidIndexName(format!(
+                "{} resolves outside base_dir",
+                index
+            ))),
+            _ => Ok(dir),
+        }
+    }
+}
+
+/// The engine the index tests share, configured like the server from
+/// `config/app.yml`.
+#[cfg(test)]
+fn test_engine() -> Engine {
+    #[derive(Deserialize)]
+    struct AppConf {
+        index: IndexConf,
+    }
+    let f = fs::File::open("config/app.yml").unwrap();
+    Engine::new(serde_yaml::from_reader::<_, AppConf>(f).unwrap().index)
+}
+
+#[test]
+fn test_index_name() {
+    assert!(is_valid_index_name("book"));
+    assert!(is_valid_index_name("logs-2021.06_a"));
+    assert!(!is_valid_index_name(""));
+    assert!(!is_valid_index_name(".."));
+    assert!(!is_valid_index_name("../etc"));
+    assert!(!is_valid_index_name("/etc"));
+    assert!(!is_valid_index_name("a/b"));
+    assert!(!is_valid_index_name(".hidden"));
+    assert!(!is_valid_index_name(&"a".repeat(129)));
+}
+
+#[test]
+fn test_merge_policy() {
+    let mut engine = test_engine();
+    engine.conf.is_merge = false;
+    assert_eq!(engine.merge_policy("book"), MergePolicyConf::None);
+    engine.conf.is_merge = true;
+    assert_eq!(
+        engine.merge_policy("book"),
+        MergePolicyConf::Log(LogMergeConf::default())
+    );
+    engine.conf.indices.insert(
+        "logs".to_string(),
+        IndexSettings {
+            commit: None,
+            merge: Some(MergePolicyConf::None),
+        },
+    );
+    assert_eq!(engine.merge_policy("logs"), MergePolicyConf::None);
 }
@@ -0,0 +1,242 @@
+use crate::error::{Result, ServerError};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tantivy::{Index, IndexReader, ReloadPolicy, TantivyError};
+
+use super::writer::WriterTask;
+use super::Engine;
+
+/// The open indices, each closed by a reaper thread once it goes unused for
+/// `idle`.
+pub(super) struct Registry {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    open: Mutex<Open>,
+    /// Signalled whenever an index finishes opening or closing.
+    settled: Condvar,
+    idle: Duration,
+}
+
+#[derive(Default)]
+struct Open {
+    handles: HashMap<String, Arc<IndexHandle>>,
+    /// Indices evicted but still closing, which may not be opened again
+    /// until their writer is gone.
+    closing: HashSet<String>,
+    /// Indices being opened, outside the lock, by one request that the
+    /// others on them wait for.
+    opening: HashSet<String>,
+}
+
+/// One open index, with the reader and writer task every request on it
+/// shares.
+pub(super) struct IndexHandle {
+    pub index: Index,
+    /// Reloaded after each commit made here, and by tantivy when another
+    /// process commits.
+    pub reader: IndexReader,
+    /// Every add, delete and upload goes through this task.
+    pub writer: Mutex<Option<WriterTask>>,
+    last_used: Mutex<Instant>,
+}
+
+impl Registry {
+    pub fn new(idle: Duration) -> Registry {
+        let shared = Arc::new(Shared {
+            open: Mutex::new(Open::default()),
+            settled: Condvar::new(),
+            idle,
+        });
+        let reaper = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("index-reaper".to_string())
+            .spawn(move || reap(reaper))
+            .expect("failed to start the index reaper");
+        Registry { shared }
+    }
+}
+
+/// Closes idle indices until the registry is dropped.
+fn reap(shared: Weak<Shared>) {
+    loop {
+        let tick = match shared.upgrade() {
+            Some(shared) => {
+                shared.close_idle();
+                (shared.idle / 2).clamp(Duration::from_millis(100), Duration::from_secs(30))
+            }
+            None => return,
+        };
+        thread::sleep(tick);
+    }
+}
+
+impl Shared {
+    fn close_idle(&self) {
+        let evicted = {
+            let mut open = self.open.lock().unwrap();
+            let evicted = evict_idle(&mut open.handles, self.idle);
+            open.closing
+                .extend(evicted.iter().map(|(name, _)| name.clone()));
+            evicted
+        };
+        self.close(evicted);
+    }
+
+    /// Closes handles already marked as closing, then lets their names be
+    /// opened again.
+    fn close(&self, handles: Vec<(String, Arc<IndexHandle>)>) {
+        for (name, handle) in handles {
+            handle.close(&name);
+            self.open.lock().unwrap().closing.remove(&name);
+            self.settled.notify_all();
+        }
+    }
+}
+
+impl Engine {
+    /// The shared handle of an index, opened on first use. Only valid names
+    /// are ever opened, so a cached one needs no further checks. The index
+    /// is opened without holding the lock, so requests to other indices go
+    /// on meanwhile.
+    pub(super) fn handle(&self, index: &str) -> Result<Arc<IndexHandle>> {
+        let shared = &self.registry.shared;
+        let mut open = shared.open.lock().unwrap();
+        // An index being closed is opened again once its writer is gone, and
+        // one another request is opening is waited for.
+        while open.closing.contains(index) || open.opening.contains(index) {
+            open = shared.settled.wait(open).unwrap();
+        }
+        let handle = match open.handles.get(index) {
+            Some(handle) => {
+                let handle = handle.clone();
+                drop(open);
+                handle
+            }
+            None => {
+                open.opening.insert(index.to_string());
+                drop(open);
+                let opened = self.open_handle(index).map(Arc::new);
+                let mut open = shared.open.lock().unwrap();
+                open.opening.remove(index);
+                if let Ok(ref handle) = opened {
+                    open.handles.insert(index.to_string(), handle.clone());
+                }
+                drop(open);
+                shared.settled.notify_all();
+                opened?
+            }
+        };
+        *handle.last_used.lock().unwrap() = Instant::now();
+        Ok(handle)
+    }
+
+    /// The handle of an index if it is open, without counting as a use.
+    pub(super) fn cached_handle(&self, index: &str) -> Option<Arc<IndexHandle>> {
+        let open = self.registry.shared.open.lock().unwrap();
+        open.handles.get(index).cloned()
+    }
+
+    /// Opens an index on its own, outside the registry, for a look at its
+    /// files.
+    pub(super) fn open_index(&self, name: &str) -> Result<Index> {
+        Index::open_in_dir(self.index_dir(name)?).map_err(|e| match e {
+            TantivyError::OpenDirectoryError(_) | TantivyError::OpenReadError(_) => {
+                ServerError::IndexNotFound(name.to_string())
+            }
+            e => e.into(),
+        })
+    }
+
+    fn open_handle(&self, name: &str) -> Result<IndexHandle> {
+        let index = self.open_index(name)?;
+        index.tokenizers().register("jieba", self.jieba.clone());
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+        info!("opened index {}", name);
+        Ok(IndexHandle {
+            index,
+            reader,
+            writer: Mutex::new(None),
+            last_used: Mutex::new(Instant::now()),
+        })
+    }
+}
+
+impl Engine {
+    /// Closes every open index, committing what their commit policies hold
+    /// back. For a server shutting down, once no request is running.
+    pub fn close(&self) {
+        let shared = &self.registry.shared;
+        let handles: Vec<(String, Arc<IndexHandle>)> = {
+            let mut open = shared.open.lock().unwrap();
+            let handles: Vec<_> = open.handles.drain().collect();
+            open.closing
+                .extend(handles.iter().map(|(name, _)| name.clone()));
+            handles
+        };
+        shared.close(handles);
+        // Indices the reaper is closing are waited for too.
+        let mut open = shared.open.lock().unwrap();
+        while !open.closing.is_empty() {
+            open = shared.settled.wait(open).unwrap();
+        }
+    }
+}
+
+impl IndexHandle {
+    /// Stops the writer task, so the segments it leaves behind are complete.
+    fn close(&self, name: &str) {
+        if let Some(task) = self.writer.lock().unwrap().take() {
+            task.stop(name);
+        }
+        info!("closed index {}", name);
+    }
+}
+
+/// Removes the handles unused for `idle` that no request holds.
+fn evict_idle(
+    handles: &mut HashMap<String, Arc<IndexHandle>>,
+    idle: Duration,
+) -> Vec<(String, Arc<IndexHandle>)> {
+    let stale: Vec<String> = handles
+        .iter()
+        .filter(|(_, handle)| {
+            Arc::strong_count(handle) == 1 && handle.last_used.lock().unwrap().elapsed() >= idle
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    stale
+        .into_iter()
+        .filter_map(|name| handles.remove_entry(&name))
+        .collect()
+}
+
+#[test]
+fn test_evict_idle() {
+    use tantivy::schema::Schema;
+
+    let index = Index::create_in_ram(Schema::builder().build());
+    let handle = Arc::new(IndexHandle {
+        reader: index.reader().unwrap(),
+        index,
+        writer: Mutex::new(None),
+        last_used: Mutex::new(Instant::now() - Duration::from_secs(10)),
+    });
+    let mut handles = HashMap::new();
+    handles.insert("book".to_string(), handle.clone());
+    // Still held by a request.
+    assert!(evict_idle(&mut handles, Duration::from_secs(5)).is_empty());
+    drop(handle);
+    assert!(evict_idle(&mut handles, Duration::from_secs(60)).is_empty());
+    let evicted = evict_idle(&mut handles, Duration::from_secs(5));
+    assert_eq!(evicted.len(), 1);
+    assert_eq!(evicted[0].0, "book");
+    assert!(handles.is_empty());
+}
@@ -1,163 +1,156 @@
-use crate::{CONF, RE};
-use serde::Deserialize;
-use serde_json::Value;
-use std::io::{Error, ErrorKind, Result};
-use std::{
-    collections::{HashMap, HashSet},
-    path::Path,
-};
+use crate::error::{Result, ServerError};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use tantivy::{
     collector::{Count, TopDocs},
-    query::QueryParser,
+    query::{QueryParser, TermQuery},
     schema::Field,
-    schema::FieldType,
-    Document, Index, SnippetGenerator,
+    schema::{FieldType, IndexRecordOption},
+    Document, SnippetGenerator, Term,
 };
+use tantivy_server_protocol::{DocValue, IndexQuery, QueryItem, SearchResult};
 
-use super::jieba_tokenizer;
+use super::doc::doc_value;
+use super::Engine;
 
-#[derive(Deserialize, Debug)]
-pub struct IndexQuery {
-    index: String,
-    param: String,
-    size: usize,
-    offset: usize,
+lazy_static! {
+    static ref RE: Regex = Regex::new(r"([[:word:]]+):").unwrap();
 }
 
-pub fn search_index(query_json: &str) -> Result<HashMap<String, Value>> {
-    let mut index_query = serde_json::from_str::<IndexQuery>(query_json)?;
-
-    if index_query.size > 120 {
-        index_query.size = 120;
-    }
-    let index = Index::open_in_dir(Path::new(&CONF.index.base_dir).join(index_query.index))
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("search_index: open_in_dir: {}", e),
-            )
-        })?;
-    index
-        .tokenizers()
-        .register("jieba", jieba_tokenizer::JiebaTokenizer {});
-    let schema = index.schema();
-    let default_fields: Vec<Field> = schema
-        .fields()
-        .filter(|&(_, field_entry)| match field_entry.field_type() {
-            FieldType::Str(ref text_field_options) => {
-                if let Some(opt) = text_field_options.get_indexing_options() {
-                    opt.tokenizer() != "raw"
-                } else {
-                    false
+impl Engine {
+    pub fn search(&self, mut index_query: IndexQuery) -> Result<SearchResult> {
+        if index_query.size > self.conf.max_page_size {
+            index_query.size = self.conf.max_page_size;
+        }
+        if index_query.offset > self.conf.max_offset {
+            return Err(ServerError::BadRequest(format!(
+                "offset {} exceeds max_offset {}",
+                index_query.offset, self.conf.max_offset
+            )));
+        }
+        let handle = self.handle(&index_query.index)?;
+        let schema = handle.index.schema();
+        let default_fields: Vec<Field> = schema
+            .fields()
+            .filter(|&(_, field_entry)| match field_entry.field_type() {
+                FieldType::Str(ref text_field_options) => {
+                    if let Some(opt) = text_field_options.get_indexing_options() {
+                        opt.tokenizer() != "raw"
+                    } else {
+                        false
+                    }
+                    // text_field_options.get_indexing_options().is_some()
                 }
-                // text_field_options.get_indexing_options().is_some()
-            }
-            _ => false,
-        })
-        .map(|(field, _)| field)
-        .collect();
-    let query_parser = QueryParser::new(
-        schema.clone(),
-        default_fields.clone(),
-        index.tokenizers().clone(),
-    );
-    // let query_parser = QueryParser::for_index(&index, vec![title, body]);
-    let reader = index
-        .reader()
-        .map_err(|e| Error::new(ErrorKind::Other, format!("Index reader: {}", e)))?;
-
-    let query = query_parser
-        .parse_query(&index_query.param)
-        .map_err(|e| Error::new(ErrorKind::Other, format!("Parsing the query failed: {}", e)))?;
-    let searcher = reader.searcher();
-    let (top_docs, count) = {
-        searcher
-            .search(
+                _ => false,
+            })
+            .map(|(field, _)| field)
+            .collect();
+        let query_parser = QueryParser::new(
+            schema.clone(),
+            default_fields.clone(),
+            handle.index.tokenizers().clone(),
+        );
+        // let query_parser = QueryParser::for_index(&index, vec![title, body]);
+        let query = query_parser.parse_query(&index_query.param)?;
+        let searcher = handle.reader.searcher();
+        // A size of 0 only counts: tantivy refuses to collect no documents.
+        let (top_docs, count) = if index_query.size == 0 {
+            (Vec::new(), searcher.search(&query, &Count)?)
+        } else {
+            searcher.search(
                 &query,
                 &(
                     TopDocs::with_limit(index_query.size).and_offset(index_query.offset),
                     Count,
                 ),
-            )
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Searcher search: {}", e)))?
-    };
-
-    let mut snippet_map: HashMap<String, SnippetGenerator> = HashMap::new();
-    {
-        let query_field = extract_field(&index_query.param);
-        if query_field.is_empty() {
-            for f in &default_fields {
-                let fname = schema.get_field_name(*f).to_string();
-                snippet_map.insert(
-                    fname,
-                    SnippetGenerator::create(&searcher, &*query, *f).map_err(|e| {
-                        Error::new(ErrorKind::Other, format!("SnippetGenerator create: {}", e))
-                    })?,
-                );
-            }
-        } else {
-            for f in &default_fields {
-                let fname = schema.get_field_name(*f).to_string();
-                if query_field.contains(&fname) {
-                    snippet_map.insert(
-                        fname,
-                        SnippetGenerator::create(&searcher, &*query, *f).map_err(|e| {
-                            Error::new(ErrorKind::Other, format!("SnippetGenerator create: {}", e))
-                        })?,
-                    );
+            )?
+        };
+
+        let mut snippet_map: HashMap<String, SnippetGenerator> = HashMap::new();
+        if index_query.highlight {
+            let query_field = extract_field(&index_query.param);
+            if query_field.is_empty() {
+                for f in &default_fields {
+                    let fname = schema.get_field_name(*f).to_string();
+                    snippet_map.insert(fname, SnippetGenerator::create(&searcher, &*query, *f)?);
+                }
+            } else {
+                for f in &default_fields {
+                    let fname = schema.get_field_name(*f).to_string();
+                    if query_field.contains(&fname) {
+                        snippet_map
+                            .insert(fname, SnippetGenerator::create(&searcher, &*query, *f)?);
+                    }
                 }
             }
         }
-    }
-    // let snippet_generator =
-    //     SnippetGenerator::create(&searcher, &*query, schema.get_field("body").unwrap())
-    //         ?;
-
-    let mut result: HashMap<String, Value> = HashMap::with_capacity(2);
-    result.insert("Total".to_string(), serde_json::to_value(count).unwrap());
-    result.insert(
-        "Data".to_string(),
-        serde_json::to_value::<Vec<HashMap<String, Value>>>(
-            top_docs
-                .iter()
-                .map(|(_, doc_address)| {
-                    // .map(|(score, doc_address)| {
-                    let doc: Document = searcher.doc(*doc_address).unwrap();
-                    let mut content: HashMap<String, Value> = HashMap::new();
-                    let named_doc = schema.to_named_doc(&doc).0;
-                    for f in named_doc.keys() {
-                        if !default_fields.contains(&schema.get_field(&f.to_string()).unwrap()) {
-                            content.insert(
-                                f.to_string(),
-                                serde_json::to_value(named_doc[f].get(0)).unwrap(),
-                            );
-                        }
-                    }
-
-                    for (f, g) in snippet_map.iter() {
+        // let snippet_generator =
+        //     SnippetGenerator::create(&searcher, &*query, schema.get_field("body").unwrap())
+        //         ?;
+
+        let hits = top_docs
+            .iter()
+            .map(|(_, doc_address)| {
+                let doc: Document = searcher.doc(*doc_address).unwrap();
+                let mut content: HashMap<String, DocValue> = HashMap::new();
+                let named_doc = schema.to_named_doc(&doc).0;
+                for f in named_doc.keys() {
+                    if !index_query.highlight
+                        || !default_fields.contains(&schema.get_field(&f.to_string()).unwrap())
+                    {
                         content.insert(
                             f.to_string(),
-                            serde_json::to_value(g.snippet_from_doc(&doc).to_html()).unwrap(),
+                            named_doc[f].get(0).map(doc_value).unwrap_or(DocValue::Null),
                         );
                     }
+                }
+
+                for (f, g) in snippet_map.iter() {
+                    content.insert(
+                        f.to_string(),
+                        DocValue::from(g.snippet_from_doc(&doc).to_html()),
+                    );
+                }
+
+                // content.insert(
+                //     "Snippet".to_string(),
+                //     serde_json::to_value(snippet).unwrap(),
+                // );
+
+                // content.insert(
+                //     "highlighting".to_string(),
+                //     serde_json::Value::String(highlight(snippet)),
+                // );
+                content
+            })
+            .collect();
+
+        let scores = top_docs.iter().map(|(score, _)| *score).collect();
+
+        Ok(SearchResult {
+            total: count,
+            hits,
+            scores,
+        })
+    }
 
-                    // content.insert(
-                    //     "Snippet".to_string(),
-                    //     serde_json::to_value(snippet).unwrap(),
-                    // );
-
-                    // content.insert(
-                    //     "highlighting".to_string(),
-                    //     serde_json::Value::String(highlight(snippet)),
-                    // );
-                    content
-                })
-                .collect(),
-        )
-        .unwrap(),
-    );
-
-    Ok(result)
+    /// How many committed documents hold the term `text` in `field`.
+    pub fn count_term(&self, item: &QueryItem) -> Result<u64> {
+        let handle = self.handle(&item.index)?;
+        let field = handle
+            .index
+            .schema()
+            .get_field(&item.field)
+            .ok_or_else(|| {
+                ServerError::BadRequest(format!("field {} does not exist", item.field))
+            })?;
+        let query = TermQuery::new(
+            Term::from_field_text(field, &item.text),
+            IndexRecordOption::Basic,
+        );
+        Ok(handle.reader.searcher().search(&query, &Count)? as u64)
+    }
 }
 
 // fn highlight(snippet: Snippet) -> String {
@@ -190,7 +183,7 @@ fn test_search_index() {
     let query = "{\"index\":\"book\",\"param\":\"book_id:\\\"l1\\\"\",\"size\":20,\"offset\":0}";
     // let query = "{\"index\":\"book\",\"param\":\"H:\\\"99\\\"\",\"size\":20,\"offset\":0}";
 
-    match search_index(query) {
+    match super::test_engine().search(serde_json::from_str::<IndexQuery>(query).unwrap()) {
         Ok(res) => {
             println!("{:#?}", res);
         }
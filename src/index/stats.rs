@@ -0,0 +1,52 @@
+use crate::error::{Result, ServerError};
+use std::fs;
+use std::io::ErrorKind;
+use tantivy_server_protocol::IndexStats;
+
+use super::{is_valid_index_name, Engine};
+
+impl Engine {
+    /// Counts for every index under `base_dir` that `include` accepts, by
+    /// name. Directories that hold no index are skipped, and no index is
+    /// opened for it or kept from closing.
+    pub fn stats<F: Fn(&str) -> bool>(&self, include: F) -> Result<Vec<IndexStats>> {
+        let entries = match fs::read_dir(&self.conf.base_dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut stats = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if is_valid_index_name(&name) && include(&name) => name,
+                _ => continue,
+            };
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            // An index that is not open is read from its files, so it is
+            // neither opened nor kept from going idle.
+            let metas = match self.cached_handle(&name) {
+                Some(handle) => handle.index.load_metas()?,
+                None => match self.open_index(&name) {
+                    Ok(index) => index.load_metas()?,
+                    Err(ServerError::IndexNotFound(_)) => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+            stats.push(IndexStats {
+                index: name,
+                num_docs: metas.segments.iter().map(|s| s.num_docs() as u64).sum(),
+                num_deleted_docs: metas
+                    .segments
+                    .iter()
+                    .map(|s| s.num_deleted_docs() as u64)
+                    .sum(),
+                segments: metas.segments.len(),
+            });
+        }
+        stats.sort_by(|a, b| a.index.cmp(&b.index));
+        Ok(stats)
+    }
+}
@@ -0,0 +1,234 @@
+use crate::error::{Result, ServerError};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{is_valid_index_name, Engine};
+
+/// The file in each index directory holding its uncommitted operations.
+pub(super) const WAL_FILE: &str = "wal.log";
+
+/// One JSON line in the log: an operation and its place in the log, which
+/// tells it from those a commit already holds.
+#[derive(Serialize, Deserialize)]
+struct Entry<R> {
+    seq: u64,
+    op: R,
+}
+
+/// An acknowledged but uncommitted operation.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Record {
+    /// Documents in tantivy's JSON form.
+    Add(Vec<String>),
+    Delete {
+        field: String,
+        text: String,
+    },
+    /// Deletes by field and text, then documents added in their place.
+    Replace {
+        deletes: Vec<(String, String)>,
+        docs: Vec<String>,
+    },
+    DeleteAll,
+}
+
+/// The write-ahead log of one index: the operations applied since its last
+/// commit, replayed into the writer when the index is opened again after a
+/// crash.
+pub(super) struct Wal {
+    path: PathBuf,
+    file: File,
+    /// The position of the next record appended.
+    next_seq: u64,
+    /// The length of the log up to the end of its last whole record.
+    len: u64,
+    /// Whether the log may end in part of a record, which the next one
+    /// must not run into.
+    torn: bool,
+}
+
+impl Engine {
+    /// Starts the writer of every index whose log holds operations, which
+    /// replays and commits them, so a crash loses nothing it acknowledged.
+    pub(super) fn recover(&self) {
+        let entries = match fs::read_dir(&self.conf.base_dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = match entry.file_name().into_string() {
+                Ok(name) if is_valid_index_name(&name) => name,
+                _ => continue,
+            };
+            match fs::metadata(entry.path().join(WAL_FILE)) {
+                Ok(meta) if meta.len() > 0 => {}
+                _ => continue,
+            }
+            info!("recovering index {}", name);
+            let result = self
+                .handle(&name)
+                .and_then(|handle| self.queue(&handle, &name));
+            if let Err(e) = result {
+                error!("recovering index {}, err={:?}", name, e);
+            }
+        }
+    }
+}
+
+impl Wal {
+    /// Opens the log, numbering new records after both `committed`, the
+    /// position the last commit holds, and those already in the log.
+    pub fn open(path: &Path, committed: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        let mut wal = Wal {
+            path: path.to_path_buf(),
+            file,
+            next_seq: 0,
+            len,
+            torn: false,
+        };
+        let last = wal.records()?.last().map_or(0, |(seq, _)| *seq);
+        wal.next_seq = last.max(committed) + 1;
+        // Cut short by a crash in the middle of an append.
+        if len > 0 {
+            let mut end = [0u8; 1];
+            wal.file.seek(SeekFrom::End(-1))?;
+            wal.file.read_exact(&mut end)?;
+            wal.torn = end[0] != b'\n';
+        }
+        Ok(wal)
+    }
+
+    /// Writes the record through to disk before returning. If that fails,
+    /// what was written of it is cut off again, or, failing that, the next
+    /// record starts on a line of its own.
+    pub fn append(&mut self, record: &Record) -> Result<()> {
+        let mut line = Vec::new();
+        if self.torn {
+            line.push(b'\n');
+        }
+        serde_json::to_writer(
+            &mut line,
+            &Entry {
+                seq: self.next_seq,
+                op: record,
+            },
+        )
+        .map_err(|e| ServerError::Internal(e.to_string()))?;
+        line.push(b'\n');
+        let written = self
+            .file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            self.torn = self.file.set_len(self.len).is_err();
+            return Err(e.into());
+        }
+        self.len += line.len() as u64;
+        self.torn = false;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// The position of the last record appended, 0 before the first.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// The records in the log with their positions, in the order they were
+    /// appended. A line that cannot be read, like one cut short by a crash,
+    /// is skipped.
+    pub fn records(&mut self) -> Result<Vec<(u64, Record)>> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut records = Vec::new();
+        for (i, line) in BufReader::new(&self.file).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Entry<Record>>(&line) {
+                Ok(entry) => records.push((entry.seq, entry.op)),
+                Err(e) => error!("{}:{} skipped, err={:?}", self.path.display(), i + 1, e),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Empties the log, once what it holds is committed.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.len = 0;
+        self.torn = false;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_wal() {
+    let dir = std::env::temp_dir().join(format!("tantivy-server-wal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(WAL_FILE);
+    let mut wal = Wal::open(&path, 0).unwrap();
+    assert_eq!(wal.last_seq(), 0);
+    wal.append(&Record::Add(vec![r#"{"title":["rust"]}"#.to_string()]))
+        .unwrap();
+    wal.append(&Record::Delete {
+        field: "title".to_string(),
+        text: "go".to_string(),
+    })
+    .unwrap();
+    assert_eq!(wal.last_seq(), 2);
+    // A crash in the middle of an append.
+    wal.file.write_all(br#"{"seq":3,"op":"delete_al"#).unwrap();
+    drop(wal);
+
+    let mut wal = Wal::open(&path, 0).unwrap();
+    let records = wal.records().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        records[1],
+        (
+            2,
+            Record::Delete {
+                field: "title".to_string(),
+                text: "go".to_string(),
+            }
+        )
+    );
+    // The next record starts after the cut-short one, not in it.
+    wal.append(&Record::DeleteAll).unwrap();
+    assert_eq!(wal.records().unwrap()[2], (3, Record::DeleteAll));
+    wal.truncate().unwrap();
+    wal.append(&Record::DeleteAll).unwrap();
+    assert_eq!(wal.records().unwrap(), vec![(4, Record::DeleteAll)]);
+
+    // An append that fails partway, on a handle that can neither write nor
+    // cut the log back, leaves a fragment the next record does not run into.
+    wal.file.write_all(br#"{"seq":5,"op":{"add":"#).unwrap();
+    let file = std::mem::replace(&mut wal.file, File::open(&path).unwrap());
+    assert!(wal.append(&Record::DeleteAll).is_err());
+    wal.file = file;
+    wal.append(&Record::DeleteAll).unwrap();
+    assert_eq!(
+        wal.records().unwrap(),
+        vec![(4, Record::DeleteAll), (5, Record::DeleteAll)]
+    );
+    drop(wal);
+
+    // Numbered after what a commit holds, even once the log is emptied.
+    let mut wal = Wal::open(&path, 7).unwrap();
+    wal.truncate().unwrap();
+    wal.append(&Record::DeleteAll).unwrap();
+    assert_eq!(wal.records().unwrap(), vec![(8, Record::DeleteAll)]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,758 @@
+use crate::error::{Result, ServerError};
+use futures::executor::block_on;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tantivy::merge_policy::{LogMergePolicy, MergePolicy, NoMergePolicy};
+use tantivy::schema::{Field, Schema};
+use tantivy::{Document, Index, IndexReader, IndexWriter, SegmentId, TantivyError, Term};
+
+use super::registry::IndexHandle;
+use super::wal::{Record, Wal, WAL_FILE};
+use super::{CommitPolicy, Engine, MergePolicyConf};
+
+/// A mutation of an index. Its writer task applies them one at a time, in
+/// the order they were queued.
+pub(super) enum Op {
+    /// Adds the documents, committing them as the commit policy says.
+    Add(Vec<Document>),
+    /// Deletes the documents whose field holds the text, or every document,
+    /// committing as the commit policy says.
+    Delete(Option<(Field, String)>),
+    /// Deletes the documents whose field holds the text, for each pair, and
+    /// adds the documents, so one commit holds both.
+    Replace(Vec<(Field, String)>, Vec<Document>),
+    Commit,
+    /// Commits what is pending, merges segments until at most this many are
+    /// left and removes the files no longer used.
+    Merge(usize),
+    /// Commits what is pending and starts a chunked upload. Until it ends,
+    /// or goes `upload_idle_secs` without a chunk and is rolled back, every
+    /// other operation on the index fails with `WriterLocked`.
+    BeginUpload(u64),
+    /// Adds a chunk of the upload's documents, uncommitted.
+    UploadChunk(u64, Vec<Document>),
+    /// Commits the upload, whatever the commit policy.
+    EndUpload(u64),
+    AbortUpload(u64),
+}
+
+struct Request {
+    op: Op,
+    /// Receives the opstamp of the operation.
+    reply: Sender<Result<u64>>,
+}
+
+/// The thread owning an index's writer, started on the first mutation so an
+/// index that is only searched never takes the writer lock or its heap.
+pub(super) struct WriterTask {
+    queue: Queue,
+    thread: JoinHandle<()>,
+}
+
+/// The bounded queue in front of a writer task.
+#[derive(Clone)]
+pub(super) struct Queue {
+    index: String,
+    sender: SyncSender<Request>,
+}
+
+impl Engine {
+    /// Queues `op` on the index's writer task and waits for its opstamp.
+    pub(super) fn write(&self, handle: &IndexHandle, index: &str, op: Op) -> Result<u64> {
+        self.queue(handle, index)?.submit(op)
+    }
+
+    /// The queue of the index's writer task, starting the task if it is not
+    /// running.
+    pub(super) fn queue(&self, handle: &IndexHandle, index: &str) -> Result<Queue> {
+        let mut task = handle.writer.lock().unwrap();
+        match *task {
+            // Only a panic ends a task whose queue is still held here.
+            Some(ref task) if !task.thread.is_finished() => return Ok(task.queue.clone()),
+            Some(_) => error!("writer of index {} stopped, restarting it", index),
+            None => {}
+        }
+        let committed_seq = committed_seq(&handle.index)?;
+        let mut writer = Writer {
+            index: index.to_string(),
+            schema: handle.index.schema(),
+            index_writer: self.open_writer(handle, index)?,
+            reader: handle.reader.clone(),
+            wal: Wal::open(&self.index_dir(index)?.join(WAL_FILE), committed_seq)?,
+            committed_seq,
+            policy: self.commit_policy(index),
+            dirty: false,
+            pending_ops: 0,
+            due: None,
+            upload: None,
+            upload_idle: Duration::from_secs(self.conf.upload_idle_secs),
+            expired_upload: None,
+        };
+        writer.recover()?;
+        let (sender, requests) = mpsc::sync_channel(self.conf.writer_queue_size);
+        let thread = thread::Builder::new()
+            .name(format!("writer-{}", index))
+            .spawn(move || writer.run(requests))?;
+        let queue = Queue {
+            index: index.to_string(),
+            sender,
+        };
+        *task = Some(WriterTask {
+            queue: queue.clone(),
+            thread,
+        });
+        Ok(queue)
+    }
+
+    fn open_writer(&self, handle: &IndexHandle, index: &str) -> Result<IndexWriter> {
+        let index_writer = handle
+            .index
+            // .writer_with_num_threads(
+            //     self.conf.thread_num,
+            //     self.conf.total_heap_size * 1024 * 1024,
+            // )
+            .writer(self.conf.total_heap_size * 1024 * 1024)?;
+        index_writer.set_merge_policy(merge_policy(self.merge_policy(index)));
+        Ok(index_writer)
+    }
+}
+
+impl WriterTask {
+    /// Closes the queue and waits for the task to apply what it holds,
+    /// commit it and let its merges finish.
+    pub fn stop(self, index: &str) {
+        drop(self.queue);
+        if self.thread.join().is_err() {
+            error!("writer of index {} panicked", index);
+        }
+    }
+}
+
+impl Queue {
+    /// Queues `op` and waits for its opstamp. Fails with `QueueFull` rather
+    /// than waiting for room.
+    pub fn submit(&self, op: Op) -> Result<u64> {
+        let (reply, result) = mpsc::channel();
+        match self.sender.try_send(Request { op, reply }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(ServerError::QueueFull(self.index.clone())),
+            Err(TrySendError::Disconnected(_)) => return Err(self.stopped()),
+        }
+        result.recv().unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    /// Queues `op`, waiting for room, and then for its opstamp. For the
+    /// operations of an upload the queue already admitted.
+    pub fn submit_wait(&self, op: Op) -> Result<u64> {
+        let (reply, result) = mpsc::channel();
+        self.sender
+            .send(Request { op, reply })
+            .map_err(|_| self.stopped())?;
+        result.recv().unwrap_or_else(|_| Err(self.stopped()))
+    }
+
+    fn stopped(&self) -> ServerError {
+        ServerError::Internal(format!("the writer of index {} stopped", self.index))
+    }
+}
+
+struct Writer {
+    index: String,
+    schema: Schema,
+    index_writer: IndexWriter,
+    reader: IndexReader,
+    /// What was applied since the last commit, while the policy holds
+    /// commits back.
+    wal: Wal,
+    /// The position of the last log record the index's last commit holds.
+    committed_seq: u64,
+    policy: CommitPolicy,
+    /// Whether adds or deletes await a commit.
+    dirty: bool,
+    /// Documents added and deletes applied since the last commit.
+    pending_ops: usize,
+    /// When an `IntervalMs` policy commits what is pending.
+    due: Option<Instant>,
+    /// The upload in progress, and when it is rolled back unless another
+    /// chunk comes.
+    upload: Option<(u64, Instant)>,
+    upload_idle: Duration,
+    /// The last upload rolled back for going idle, so that its client is
+    /// told why.
+    expired_upload: Option<u64>,
+}
+
+impl Writer {
+    fn run(mut self, requests: Receiver<Request>) {
+        loop {
+            let deadline = match (self.due, self.upload) {
+                (Some(due), Some((_, idle))) => Some(due.min(idle)),
+                (due, upload) => due.or(upload.map(|(_, idle)| idle)),
+            };
+            let request = match deadline {
+                Some(deadline) => {
+                    match requests.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(request) => request,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.expire_upload();
+                            if self.due.is_some_and(|due| due <= Instant::now()) {
+                                if let Err(e) = self.commit() {
+                                    error!("commit of index {}, err={:?}", self.index, e);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => break,
+                },
+            };
+            let result = self.apply(request.op);
+            // The caller may be gone; the operation stands regardless.
+            let _ = request.reply.send(result);
+        }
+        if self.upload.is_some() {
+            self.rollback();
+        }
+        if self.dirty {
+            if let Err(e) = self.commit() {
+                error!("commit of index {}, err={:?}", self.index, e);
+            }
+        }
+        if let Err(e) = self.index_writer.wait_merging_threads() {
+            error!("closing writer of index {}, err={:?}", self.index, e);
+        }
+    }
+
+    fn apply(&mut self, op: Op) -> Result<u64> {
+        // An operation queued behind a stalled upload is not refused for it.
+        self.expire_upload();
+        if let Some((upload, _)) = self.upload {
+            return match op {
+                Op::UploadChunk(id, docs) if id == upload => {
+                    self.upload = Some((id, Instant::now() + self.upload_idle));
+                    Ok(self.add(docs))
+                }
+                Op::EndUpload(id) if id == upload => {
+                    self.upload = None;
+                    self.commit()
+                }
+                Op::AbortUpload(id) if id == upload => {
+                    self.upload = None;
+                    self.rollback();
+                    Ok(self.index_writer.commit_opstamp())
+                }
+                _ => Err(ServerError::WriterLocked(format!(
+                    "an upload to {} is in progress",
+                    self.index
+                ))),
+            };
+        }
+        match op {
+            Op::Add(docs) => {
+                self.log(|schema| {
+                    Record::Add(docs.iter().map(|doc| schema.to_json(doc)).collect())
+                })?;
+                let count = docs.len();
+                let opstamp = self.add(docs);
+                self.applied(count, opstamp)
+            }
+            Op::Delete(Some((field, text))) => {
+                self.log(|schema| Record::Delete {
+                    field: schema.get_field_name(field).to_string(),
+                    text: text.clone(),
+                })?;
+                let opstamp = self
+                    .index_writer
+                    .delete_term(Term::from_field_text(field, &text));
+                self.applied(1, opstamp)
+            }
+            Op::Replace(deletes, docs) => {
+                self.log(|schema| Record::Replace {
+                    deletes: deletes
+                        .iter()
+                        .map(|(field, text)| {
+                            (schema.get_field_name(*field).to_string(), text.clone())
+                        })
+                        .collect(),
+                    docs: docs.iter().map(|doc| schema.to_json(doc)).collect(),
+                })?;
+                let count = deletes.len() + docs.len();
+                for (field, text) in deletes {
+                    self.index_writer
+                        .delete_term(Term::from_field_text(field, &text));
+                }
+                let opstamp = self.add(docs);
+                self.applied(count, opstamp)
+            }
+            Op::Delete(None) => {
+                self.log(|_| Record::DeleteAll)?;
+                let opstamp = self.index_writer.delete_all_documents()?;
+                self.applied(1, opstamp)
+            }
+            Op::Commit => self.commit(),
+            Op::Merge(max_segments) => self.merge(max_segments),
+            Op::BeginUpload(id) => {
+                // So that aborting the upload rolls back only its documents.
+                let opstamp = if self.dirty {
+                    self.commit()?
+                } else {
+                    self.index_writer.commit_opstamp()
+                };
+                self.upload = Some((id, Instant::now() + self.upload_idle));
+                Ok(opstamp)
+            }
+            Op::AbortUpload(id) if self.expired_upload == Some(id) => {
+                Ok(self.index_writer.commit_opstamp())
+            }
+            Op::UploadChunk(id, _) | Op::EndUpload(id) if self.expired_upload == Some(id) => {
+                Err(ServerError::BadRequest(format!(
+                    "the upload to {} sent no chunk for {}s and was rolled back",
+                    self.index,
+                    self.upload_idle.as_secs()
+                )))
+            }
+            // The task was restarted under the upload.
+            Op::UploadChunk(..) | Op::EndUpload(_) | Op::AbortUpload(_) => Err(
+                ServerError::Internal(format!("the upload to {} was lost", self.index)),
+            ),
+        }
+    }
+
+    /// Returns the opstamp of the last document.
+    fn add(&mut self, docs: Vec<Document>) -> u64 {
+        let mut opstamp = self.index_writer.commit_opstamp();
+        for doc in docs {
+            opstamp = self.index_writer.add_document(doc);
+        }
+        opstamp
+    }
+
+    /// Logs an operation before it is applied, when the policy may
+    /// acknowledge it uncommitted.
+    fn log<F: FnOnce(&Schema) -> Record>(&mut self, record: F) -> Result<()> {
+        if self.policy == CommitPolicy::Immediate {
+            return Ok(());
+        }
+        self.wal.append(&record(&self.schema))
+    }
+
+    /// Commits if the policy says an operation just applied should be.
+    /// `ops` counts its documents, or 1 for a delete. Returns the commit's
+    /// opstamp, or the operation's own while it waits.
+    fn applied(&mut self, ops: usize, opstamp: u64) -> Result<u64> {
+        self.pending(ops);
+        match self.policy {
+            CommitPolicy::Immediate => self.commit(),
+            CommitPolicy::Docs(max) if self.pending_ops >= max => self.commit(),
+            _ => Ok(opstamp),
+        }
+    }
+
+    fn pending(&mut self, ops: usize) {
+        self.pending_ops += ops;
+        if !self.dirty {
+            self.dirty = true;
+            if let CommitPolicy::IntervalMs(ms) = self.policy {
+                self.due = Some(Instant::now() + Duration::from_millis(ms));
+            }
+        }
+    }
+
+    /// Commits what the writer holds, empties the log and reloads the
+    /// reader, so the caller sees it once it has the opstamp. The commit
+    /// records the last log record it holds, so records left by a crash
+    /// before the log is emptied are not applied twice. If the commit
+    /// fails, the writer is rolled back and the log replayed, so what was
+    /// acknowledged stays pending.
+    fn commit(&mut self) -> Result<u64> {
+        self.dirty = false;
+        self.pending_ops = 0;
+        self.due = None;
+        let wal_seq = self.wal.last_seq();
+        let payload = serde_json::to_string(&CommitPayload { wal_seq })
+            .map_err(|e| ServerError::Internal(e.to_string()))?;
+        let result = self.index_writer.prepare_commit().and_then(|mut prepared| {
+            prepared.set_payload(&payload);
+            prepared.commit()
+        });
+        match result {
+            Ok(opstamp) => {
+                info!("Commit succeed, docstamp at {}", opstamp);
+                self.committed_seq = wal_seq;
+                // The commit stands; replay skips what the log still holds.
+                if let Err(e) = self.wal.truncate() {
+                    error!("emptying the log of index {}, err={:?}", self.index, e);
+                }
+                self.reader.reload()?;
+                Ok(opstamp)
+            }
+            Err(e) => {
+                self.rollback();
+                self.replay()?;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn merge(&mut self, max_segments: usize) -> Result<u64> {
+        let opstamp = if self.dirty {
+            self.commit()?
+        } else {
+            self.index_writer.commit_opstamp()
+        };
+        let mut segments = self.index_writer.index().searchable_segment_metas()?;
+        if segments.len() > max_segments {
+            // Merging the smallest segments into one leaves `max_segments`.
+            segments.sort_by_key(|segment| segment.num_docs());
+            let ids: Vec<SegmentId> = segments[..segments.len() - max_segments + 1]
+                .iter()
+                .map(|segment| segment.id())
+                .collect();
+            block_on(self.index_writer.merge(&ids)).map_err(|e| match e {
+                // The ids were just read from the committed segments, so the
+                // only ones tantivy refuses are those a merge already holds.
+                TantivyError::InvalidArgument(_) => {
+                    ServerError::MergeInProgress(self.index.clone())
+                }
+                e => e.into(),
+            })?;
+            self.reader.reload()?;
+            info!("merged {} segments of index {}", ids.len(), self.index);
+        }
+        let collected = block_on(self.index_writer.garbage_collect_files())?;
+        info!(
+            "removed {} unused files of index {}",
+            collected.deleted_files.len(),
+            self.index
+        );
+        Ok(opstamp)
+    }
+
+    /// Commits what the log holds from before a crash, and drops what the
+    /// last commit already held.
+    fn recover(&mut self) -> Result<()> {
+        let count = self.replay()?;
+        if count > 0 {
+            info!("replayed {} operations into index {}", count, self.index);
+            self.commit()?;
+        } else {
+            self.wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the operations in the log the last commit does not hold
+    /// again, as pending, and returns how many there were.
+    fn replay(&mut self) -> Result<usize> {
+        let committed_seq = self.committed_seq;
+        let records: Vec<Record> = self
+            .wal
+            .records()?
+            .into_iter()
+            .filter(|(seq, _)| *seq > committed_seq)
+            .map(|(_, record)| record)
+            .collect();
+        for record in &records {
+            match record {
+                Record::Add(docs) => self.replay_add(docs),
+                Record::Delete { field, text } => self.replay_delete(field, text),
+                Record::Replace { deletes, docs } => {
+                    for (field, text) in deletes {
+                        self.replay_delete(field, text);
+                    }
+                    self.replay_add(docs);
+                }
+                Record::DeleteAll => {
+                    self.index_writer.delete_all_documents()?;
+                    self.pending_ops += 1;
+                }
+            }
+        }
+        if !records.is_empty() {
+            self.pending(0);
+        }
+        Ok(records.len())
+    }
+
+    fn replay_add(&mut self, docs: &[String]) {
+        for doc in docs {
+            match self.schema.parse_document(doc) {
+                Ok(doc) => {
+                    self.index_writer.add_document(doc);
+                    self.pending_ops += 1;
+                }
+                Err(e) => error!("replay into {}, err={:?}", self.index, e),
+            }
+        }
+    }
+
+    fn replay_delete(&mut self, field: &str, text: &str) {
+        match self.schema.get_field(field) {
+            Some(field) => {
+                self.index_writer
+                    .delete_term(Term::from_field_text(field, text));
+                self.pending_ops += 1;
+            }
+            None => error!("replay into {}, no field {}", self.index, field),
+        }
+    }
+
+    /// Rolls back the upload in progress if it went without a chunk for
+    /// `upload_idle`.
+    fn expire_upload(&mut self) {
+        if let Some((id, idle)) = self.upload {
+            if idle <= Instant::now() {
+                info!(
+                    "upload to index {} sent no chunk for {:?}, rolling it back",
+                    self.index, self.upload_idle
+                );
+                self.upload = None;
+                self.expired_upload = Some(id);
+                self.rollback();
+            }
+        }
+    }
+
+    fn rollback(&mut self) {
+        if let Err(e) = self.index_writer.rollback() {
+            error!("rollback err={:?}", e);
+        }
+    }
+}
+
+/// What a commit records in its payload: the position of the last log
+/// record it holds.
+#[derive(Serialize, Deserialize)]
+struct CommitPayload {
+    wal_seq: u64,
+}
+
+/// The log position the index's last commit holds, 0 when it was made
+/// without one.
+fn committed_seq(index: &Index) -> Result<u64> {
+    Ok(index
+        .load_metas()?
+        .payload
+        .and_then(|payload| serde_json::from_str::<CommitPayload>(&payload).ok())
+        .map_or(0, |payload| payload.wal_seq))
+}
+
+fn merge_policy(conf: MergePolicyConf) -> Box<dyn MergePolicy> {
+    match conf {
+        MergePolicyConf::None => Box::new(NoMergePolicy),
+        MergePolicyConf::Log(log) => {
+            let mut policy = LogMergePolicy::default();
+            if let Some(docs) = log.min_segment_docs {
+                policy.set_min_layer_size(docs);
+            }
+            if let Some(docs) = log.max_segment_docs {
+                policy.set_max_docs_before_merge(docs);
+            }
+            if let Some(factor) = log.merge_factor {
+                policy.set_min_num_segments(factor);
+            }
+            if let Some(ratio) = log.deletes_ratio {
+                policy.set_del_docs_ratio_before_merge(ratio);
+            }
+            Box::new(policy)
+        }
+    }
+}
+
+#[test]
+fn test_queue_full() {
+    let (sender, _requests) = mpsc::sync_channel(1);
+    let queue = Queue {
+        index: "book".to_string(),
+        sender,
+    };
+    let (reply, _) = mpsc::channel();
+    queue
+        .sender
+        .try_send(Request {
+            op: Op::Delete(None),
+            reply,
+        })
+        .unwrap();
+    let e = queue.submit(Op::Delete(None)).unwrap_err();
+    assert_eq!(e.code(), 302);
+    assert_eq!(
+        e.to_string(),
+        "QueueFull: the writer queue of index book is full, retry later"
+    );
+}
+
+#[cfg(test)]
+fn test_writer(index: &Index, dir: &std::path::Path, policy: CommitPolicy) -> Writer {
+    std::fs::create_dir_all(dir).unwrap();
+    let index_writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+    index_writer.set_merge_policy(Box::new(NoMergePolicy));
+    Writer {
+        index: "book".to_string(),
+        schema: index.schema(),
+        index_writer,
+        reader: index.reader().unwrap(),
+        wal: Wal::open(&dir.join(WAL_FILE), 0).unwrap(),
+        committed_seq: 0,
+        policy,
+        dirty: false,
+        pending_ops: 0,
+        due: None,
+        upload: None,
+        upload_idle: Duration::from_secs(30),
+        expired_upload: None,
+    }
+}
+
+#[test]
+fn test_commit_policy() {
+    use tantivy::schema::{Schema, TEXT};
+
+    let mut builder = Schema::builder();
+    let title = builder.add_text_field("title", TEXT);
+    let index = Index::create_in_ram(builder.build());
+    let dir = std::env::temp_dir().join(format!("tantivy-server-writer-{}", std::process::id()));
+    let mut writer = test_writer(&index, &dir, CommitPolicy::Docs(2));
+    let reader = writer.reader.clone();
+    let doc = || {
+        let mut doc = Document::default();
+        doc.add_text(title, "rust");
+        doc
+    };
+    writer.apply(Op::Add(vec![doc()])).unwrap();
+    assert!(writer.dirty);
+    assert_eq!(reader.searcher().num_docs(), 0);
+    assert_eq!(writer.wal.records().unwrap().len(), 1);
+    writer.apply(Op::Add(vec![doc()])).unwrap();
+    assert!(!writer.dirty);
+    assert_eq!(reader.searcher().num_docs(), 2);
+    assert!(writer.wal.records().unwrap().is_empty());
+
+    // Lost from the writer, as in a crash, but not from the log.
+    writer.apply(Op::Add(vec![doc()])).unwrap();
+    writer.rollback();
+    writer.recover().unwrap();
+    assert_eq!(reader.searcher().num_docs(), 3);
+    writer.apply(Op::Add(vec![doc()])).unwrap();
+    writer.apply(Op::Commit).unwrap();
+    assert_eq!(reader.searcher().num_docs(), 4);
+    assert_eq!(committed_seq(&index).unwrap(), writer.committed_seq);
+
+    // Left in the log by a crash between a commit and emptying the log, so
+    // numbered at or below what the commit holds.
+    let mut stale = Wal::open(&dir.join(WAL_FILE), 0).unwrap();
+    stale
+        .append(&Record::Add(vec![index.schema().to_json(&doc())]))
+        .unwrap();
+    drop(stale);
+    writer.recover().unwrap();
+    assert_eq!(reader.searcher().num_docs(), 4);
+    assert!(writer.wal.records().unwrap().is_empty());
+
+    // Deletes count toward the policy too.
+    writer
+        .apply(Op::Delete(Some((title, "go".to_string()))))
+        .unwrap();
+    assert!(writer.dirty);
+    writer
+        .apply(Op::Delete(Some((title, "rust".to_string()))))
+        .unwrap();
+    assert!(!writer.dirty);
+    assert_eq!(reader.searcher().num_docs(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_merge() {
+    use tantivy::schema::{Schema, TEXT};
+
+    let mut builder = Schema::builder();
+    let title = builder.add_text_field("title", TEXT);
+    let index = Index::create_in_ram(builder.build());
+    let dir = std::env::temp_dir().join(format!("tantivy-server-merge-{}", std::process::id()));
+    let mut writer = test_writer(&index, &dir, CommitPolicy::Immediate);
+    // One segment per commit.
+    for _ in 0..4 {
+        let mut doc = Document::default();
+        doc.add_text(title, "rust");
+        writer.apply(Op::Add(vec![doc])).unwrap();
+    }
+    assert_eq!(index.searchable_segment_metas().unwrap().len(), 4);
+
+    writer.apply(Op::Merge(2)).unwrap();
+    assert_eq!(index.searchable_segment_metas().unwrap().len(), 2);
+    writer.apply(Op::Merge(1)).unwrap();
+    assert_eq!(index.searchable_segment_metas().unwrap().len(), 1);
+    // Already few enough.
+    writer.apply(Op::Merge(1)).unwrap();
+    assert_eq!(index.searchable_segment_metas().unwrap().len(), 1);
+    assert_eq!(writer.reader.searcher().num_docs(), 4);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_replace() {
+    use tantivy::schema::{Schema, STRING};
+
+    let mut builder = Schema::builder();
+    let id = builder.add_text_field("id", STRING);
+    let index = Index::create_in_ram(builder.build());
+    let dir = std::env::temp_dir().join(format!("tantivy-server-replace-{}", std::process::id()));
+    let mut writer = test_writer(&index, &dir, CommitPolicy::Immediate);
+    let doc = |text: &str| {
+        let mut doc = Document::default();
+        doc.add_text(id, text);
+        doc
+    };
+    writer.apply(Op::Add(vec![doc("1"), doc("2")])).unwrap();
+    writer
+        .apply(Op::Replace(
+            vec![(id, "1".to_string()), (id, "3".to_string())],
+            vec![doc("1"), doc("3")],
+        ))
+        .unwrap();
+    // The replaced document is gone, and the ones added in its place stay.
+    assert_eq!(writer.reader.searcher().num_docs(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_upload_idle() {
+    use tantivy::schema::{Schema, TEXT};
+
+    let mut builder = Schema::builder();
+    let title = builder.add_text_field("title", TEXT);
+    let index = Index::create_in_ram(builder.build());
+    let dir = std::env::temp_dir().join(format!("tantivy-server-upload-{}", std::process::id()));
+    let mut writer = test_writer(&index, &dir, CommitPolicy::Immediate);
+    writer.upload_idle = Duration::from_millis(50);
+    let doc = || {
+        let mut doc = Document::default();
+        doc.add_text(title, "rust");
+        doc
+    };
+    writer.apply(Op::BeginUpload(1)).unwrap();
+    writer.apply(Op::UploadChunk(1, vec![doc()])).unwrap();
+    assert!(matches!(
+        writer.apply(Op::Add(vec![doc()])),
+        Err(ServerError::WriterLocked(_))
+    ));
+    thread::sleep(Duration::from_millis(60));
+    // Once the upload stalls, it is rolled back and other writes go ahead.
+    writer.apply(Op::Add(vec![doc()])).unwrap();
+    assert_eq!(writer.reader.searcher().num_docs(), 1);
+    assert!(matches!(
+        writer.apply(Op::EndUpload(1)),
+        Err(ServerError::BadRequest(_))
+    ));
+    writer.apply(Op::AbortUpload(1)).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
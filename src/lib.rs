@@ -0,0 +1,51 @@
+//! The index engine behind tantivy-server, usable in-process without the
+//! TCP layer.
+//!
+//! An [`Engine`] is built from an [`IndexConf`], the `index` section of the
+//! server's `app.yml`, and creates, fills, searches and deletes from the
+//! indices under its `base_dir`, with the same schema options and jieba
+//! tokenizer the server uses:
+//!
+//! ```no_run
+//! use tantivy_server::protocol::IndexQuery;
+//! use tantivy_server::{CommitPolicy, Engine, IndexConf, JiebaConf, TokenizerConf};
+//!
+//! let engine = Engine::new(IndexConf {
+//!     base_dir: "indices".to_string(),
+//!     is_merge: false,
+//!     thread_num: 4,
+//!     total_heap_size: 100,
+//!     max_page_size: 120,
+//!     max_offset: 10000,
+//!     idle_close_secs: 300,
+//!     writer_queue_size: 64,
+//!     upload_idle_secs: 30,
+//!     commit: CommitPolicy::Immediate,
+//!     merge: None,
+//!     indices: Default::default(),
+//!     tokenizer: TokenizerConf {
+//!         jieba: JiebaConf {
+//!             dict_path: "config/dict.txt".to_string(),
+//!             stop_word_path: "config/stop_word.txt".to_string(),
+//!         },
+//!     },
+//! });
+//! let result = engine.search(IndexQuery {
+//!     index: "book".to_string(),
+//!     param: "title:rust".to_string(),
+//!     size: 20,
+//!     offset: 0,
+//!     highlight: true,
+//! })?;
+//! # Ok::<(), tantivy_server::ServerError>(())
+//! ```
+
+mod error;
+mod index;
+
+pub use error::{Result, ServerError};
+pub use index::{
+    Batch, CommitPolicy, Engine, IndexConf, IndexSettings, JiebaConf, LogMergeConf,
+    MergePolicyConf, TokenizerConf, Upload,
+};
+pub use tantivy_server_protocol as protocol;
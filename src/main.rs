@@ -1,168 +1,258 @@
-use index::IndexConf;
+use clap::Parser;
+use config::{Args, Config};
 use lazy_static::lazy_static;
 use log::{error, info};
 use log4rs;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use serde_yaml;
+use stream::UnixSocketConf;
+use tantivy_server::{Engine, ServerError};
 
-use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind};
-use std::net::{Shutdown, TcpListener};
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-pub mod index;
+mod auth;
+mod config;
+mod es;
+mod http;
+mod pool;
 mod server;
+mod shutdown;
+mod stream;
+mod tls;
 
-use crate::server::{Message, Status, TantivyServer};
-
-mod error;
-
-// use clap::Clap;
-
-// #[derive(Clap, Debug)]
-// enum IndexOption {
-//     // #[clap()]
-//     Index(Index),
-// }
-
-// #[derive(Clap, Debug)]
-// struct Index {
-//     /// Print debug info
-//     #[clap(short, long, default_value = "tanvity")]
-//     dir: String,
-// }
-
-// #[derive(Clap, Debug)]
-// struct Config {
-//     #[clap(short, long, default_value = "127.0.0.1:8099")]
-//     bind_addr: String,
-//     #[clap(flatten)]
-//     index: Index,
-//     // #[clap(subcommand)]
-//     // index: IndexOption
-// }
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct Config {
-    bind_addr: String,
-    byteorder: String,
-    log_config: String,
-    index: IndexConf,
-}
-
-lazy_static! {
+use crate::pool::Pool;
+use crate::server::{Connection, TantivyServer};
+use crate::shutdown::SHUTDOWN;
+use crate::stream::{Listener, Stream};
 
-    static ref RE: Regex = Regex::new(r"([[:word:]]+):").unwrap();
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
 
-    static ref CONF: Config = {
-        let f = File::open("config/app.yml").unwrap();
-        serde_yaml::from_reader(f).unwrap()
-    };
+/// Refusals written at once. Past this, connections over the limit are
+/// closed without a reply.
+const MAX_REFUSING: usize = 16;
 
-    // static ref HANDSHAKE: HashMap<String, String> = {
-    //   let mut handshake: HashMap<String,String> = HashMap::with_capacity(2);
-    //   handshake.insert("greeting".to_string(), "Tantivy Search Engine 1.0".to_string());
-
-    //   if cfg!(target_endian = "big") {
-    //     handshake.insert("target_endian".to_string(), "big".to_string());
-    //   } else {
-    //     handshake.insert("target_endian".to_string(), "little".to_string());
-    //   }
-    //   handshake
-    // };
-
-    static ref JIEBA: jieba_rs::Jieba = {
-      // println!("{}", std::env::current_dir().unwrap().display());
-      match File::open(CONF.index.tokenizer.jieba.dict_path.to_string()) {
-          Ok(r) => jieba_rs::Jieba::with_dict(&mut BufReader::new(r)).unwrap(),
-          Err(_) => jieba_rs::Jieba::new(),
-      }
-    };
+static REFUSING: AtomicUsize = AtomicUsize::new(0);
 
-    static ref STOP_WORD: Vec<String> = {
-      let mut words: Vec<String> = Vec::new();
-      if let Ok(r) = File::open(CONF.index.tokenizer.jieba.stop_word_path.to_string()) {
-        let lines = BufReader::new(r).lines();
-        for line in lines {
-          if (&line).is_ok()  {
-            let word = line.as_ref().unwrap().trim();
-            if !word.is_empty() {
-              words.push(word.to_string());
-            }
-          }
-        }
-      }
-      words
-    };
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+    static ref CONF: Config = config::load(&Args::parse()).unwrap_or_else(|e| exit_with(e));
+    static ref ENGINE: Engine = Engine::new(CONF.index.clone());
 }
 
 fn main() {
-    log4rs::init_file(CONF.log_config.to_string(), Default::default()).unwrap();
-    // let config = Config::parse();
-    let listener = TcpListener::bind(&CONF.bind_addr)
-        .expect(&format!("faild to listen: {}", &CONF.bind_addr).to_string());
+    lazy_static::initialize(&STARTED);
+    lazy_static::initialize(&CONF);
+    if let Err(e) = log4rs::init_file(&CONF.log_config, Default::default()) {
+        exit_with(format!("log_config {}: {}", CONF.log_config, e));
+    }
+    // Loads the jieba dictionary now rather than on the first request.
+    lazy_static::initialize(&ENGINE);
+    SHUTDOWN
+        .install()
+        .expect("failed to install signal handlers");
+    // Listeners are polled, so the accept loop notices a shutdown request.
+    let tcp = match CONF.tls {
+        Some(ref tls) => {
+            let config = tls.server_config().unwrap_or_else(|e| exit_with(e));
+            Listener::bind_tls(&CONF.bind_addr, config)
+        }
+        None => Listener::bind_tcp(&CONF.bind_addr),
+    };
+    let mut listeners =
+        vec![tcp.unwrap_or_else(|e| {
+            exit_with(format!("failed to listen on {}: {}", CONF.bind_addr, e))
+        })];
     info!("Server started: {}", CONF.bind_addr);
+    if let Some(ref conf) = CONF.unix_socket {
+        listeners.push(bind_unix(conf));
+        info!("Unix socket listening: {}", conf.path);
+    }
+    let http = CONF.http.as_ref().map(|conf| {
+        http::start(conf)
+            .unwrap_or_else(|e| exit_with(format!("failed to listen on {}: {}", conf.bind_addr, e)))
+    });
 
     let server = TantivyServer {};
+    let pool = Pool::new(
+        CONF.workers,
+        CONF.max_connections,
+        Duration::from_secs(CONF.queue_timeout_secs),
+        move |stream| serve(server, stream),
+    );
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                // stream
-                //     .write(&serde_json::to_vec(&*HANDSHAKE).unwrap())
-                //     .expect("fail to handshake");
-                thread::spawn(move || loop {
-                    println!("looping...");
-                    match &server.receive(&mut stream) {
-                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                            // wait until network socket is ready, typically implemented
-                            // via platform-specific APIs such as epoll or IOCP
-                            // read timed out: shutdown
-                            error!("timeout, err={:?}", e);
-                            stream.shutdown(Shutdown::Both).unwrap();
-                            break;
-                            // continue;
-                        }
-                        Err(ref e)
-                            if e.kind() == ErrorKind::UnexpectedEof
-                                || e.kind() == ErrorKind::ConnectionReset =>
-                        {
-                            error!("client disconnected, err={:?}", e);
-                            // stream
-                            //     .shutdown(Shutdown::Both)
-                            //     .map_err(|e| {
-                            //         println!("------------:{}", e);
-                            //     })
-                            //     .unwrap();
-                            break;
-                        }
-                        Err(e) => {
-                            error!("receive err={:?}", e);
-                            let _ = &server.send(
-                                &mut stream,
-                                Message {
-                                    status: Status::Wrong,
-                                    message: Some(serde_json::to_value(e.to_string()).unwrap()),
-                                },
-                            );
-                            // stream.shutdown(Shutdown::Both).unwrap();
-                            // break;
-                        }
-                        Ok(_) => {
-                            let _ = &server.send(
-                                &mut stream,
-                                Message {
-                                    status: Status::Ok,
-                                    message: None,
-                                },
-                            );
-                        }
+    while !SHUTDOWN.requested() {
+        let mut idle = true;
+        for listener in &listeners {
+            match listener.accept() {
+                Ok(stream) => {
+                    idle = false;
+                    if let Err(stream) = pool.submit(stream) {
+                        error!("refusing connection, {} connections open", pool.active());
+                        refuse(server, stream);
                     }
-                });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    error!("incoming: {}", e);
+                }
+            }
+        }
+        for stream in pool.expired() {
+            error!("refusing connection, no free worker within queue_timeout_secs");
+            refuse(server, stream);
+        }
+        if idle {
+            thread::sleep(ACCEPT_POLL);
+        }
+    }
+
+    info!(
+        "shutting down, draining for up to {}s",
+        CONF.drain_timeout_secs
+    );
+    drop(listeners);
+    if let Some(ref http) = http {
+        http.stop();
+    }
+    SHUTDOWN.wake_idle();
+    let deadline = Instant::now() + Duration::from_secs(CONF.drain_timeout_secs);
+    while pool.active() > 0 || matches!(http, Some(ref http) if !http.is_stopped()) {
+        if Instant::now() >= deadline {
+            error!("drain timeout, abandoning {} connections", pool.active());
+            process::exit(1);
+        }
+        thread::sleep(ACCEPT_POLL);
+    }
+    ENGINE.close();
+    info!("Server stopped");
+}
+
+/// Reports a failure to start and exits.
+fn exit_with<E: Display>(e: E) -> ! {
+    eprintln!("tantivy_server: {}", e);
+    process::exit(1);
+}
+
+#[cfg(unix)]
+fn bind_unix(conf: &UnixSocketConf) -> Listener {
+    Listener::bind_unix(conf)
+        .unwrap_or_else(|e| exit_with(format!("failed to listen on {}: {}", conf.path, e)))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &UnixSocketConf) -> Listener {
+    panic!("unix_socket is only supported on Unix");
+}
+
+/// Serves one connection until the client leaves or goes idle.
+fn serve(server: TantivyServer, stream: Stream) {
+    let timeout = Duration::from_secs(CONF.idle_timeout_secs);
+    if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+        error!("connection setup, err={:?}", e);
+        return;
+    }
+    let _tracked = match SHUTDOWN.track(&stream) {
+        Ok(tracked) => tracked,
+        Err(e) => {
+            error!("connection setup, err={:?}", e);
+            return;
+        }
+    };
+    // Queued before the shutdown and never started.
+    if SHUTDOWN.requested() {
+        return;
+    }
+    let mut conn = Connection::new(stream);
+    if CONF.handshake_on_connect {
+        if let Err(e) = server.greet(&mut conn) {
+            error!("handshake, err={:?}", e);
+            return;
+        }
+    }
+    loop {
+        let msg = match server.receive(&mut conn) {
+            Err(ServerError::Io(ref e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                info!("idle timeout, closing connection");
+                let _ = conn.shutdown();
+                break;
+            }
+            Err(ServerError::Io(_)) if SHUTDOWN.requested() => {
+                // Any unfinished upload is dropped with the connection,
+                // which rolls it back.
+                info!("closing connection for shutdown");
+                break;
+            }
+            Err(ServerError::Io(ref e))
+                if e.kind() == ErrorKind::UnexpectedEof
+                    || e.kind() == ErrorKind::ConnectionReset =>
+            {
+                error!("client disconnected, err={:?}", e);
+                break;
+            }
+            Err(ServerError::Io(e)) => {
+                // Includes a failed TLS handshake; the stream is unusable.
+                error!("connection err={:?}", e);
+                break;
+            }
+            Err(e @ ServerError::FrameTooLarge { .. }) => {
+                error!("receive err={:?}", e);
+                let _ = server.reply(&mut conn, None, Instant::now(), Err(e));
+                let _ = conn.close();
+                break;
             }
             Err(e) => {
-                error!("incoming: {}", e);
+                error!("receive err={:?}", e);
+                let _ = server.reply(&mut conn, None, Instant::now(), Err(e));
+                continue;
             }
+            Ok(msg) => msg,
+        };
+        let started = Instant::now();
+        let id = msg.id.clone();
+        let result = server.handle(&mut conn, msg);
+        if let Err(ref e) = result {
+            error!("handle err={:?}", e);
         }
+        let _ = server.reply(&mut conn, id, started, result);
+    }
+}
+
+/// Tells a client over the connection limit to come back later, without
+/// waiting for it to send anything. The reply, and the TLS handshake before
+/// it, run on a thread of their own, so the accept loop never waits on
+/// the client.
+fn refuse(server: TantivyServer, stream: Stream) {
+    if REFUSING.fetch_add(1, Ordering::SeqCst) >= MAX_REFUSING {
+        REFUSING.fetch_sub(1, Ordering::SeqCst);
+        // Dropping the stream closes the socket without any I/O.
+        return;
+    }
+    let spawned = thread::Builder::new()
+        .name("refuse".to_string())
+        .spawn(move || {
+            // Bounded, since a TLS client may stall the handshake.
+            let timeout = Some(Duration::from_secs(1));
+            match stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout))
+            {
+                Ok(()) => {
+                    let mut conn = Connection::new(stream);
+                    let e = ServerError::TooManyConnections(CONF.max_connections);
+                    let _ = server.reply(&mut conn, None, Instant::now(), Err(e));
+                    let _ = conn.shutdown();
+                }
+                Err(e) => error!("connection setup, err={:?}", e),
+            }
+            REFUSING.fetch_sub(1, Ordering::SeqCst);
+        });
+    if let Err(e) = spawned {
+        REFUSING.fetch_sub(1, Ordering::SeqCst);
+        error!("refusing connection, err={:?}", e);
     }
 }
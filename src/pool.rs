@@ -0,0 +1,186 @@
+use log::error;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A fixed set of worker threads serving connections one at a time. Up to
+/// `max_connections` are admitted; those beyond the number of workers wait
+/// in the queue for a free worker, for up to `queue_timeout`.
+pub struct Pool<T> {
+    shared: Arc<Shared<T>>,
+    max_connections: usize,
+    queue_timeout: Duration,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    /// Signalled when a connection is queued or the pool is dropped.
+    ready: Condvar,
+    active: AtomicUsize,
+}
+
+struct Queue<T> {
+    /// Connections waiting for a worker, oldest first, with when they came.
+    waiting: VecDeque<(Instant, T)>,
+    closed: bool,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    pub fn new<F>(
+        workers: usize,
+        max_connections: usize,
+        queue_timeout: Duration,
+        handler: F,
+    ) -> Pool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                waiting: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+            active: AtomicUsize::new(0),
+        });
+        let handler = Arc::new(handler);
+        for i in 0..workers.max(1) {
+            let shared = shared.clone();
+            let handler = handler.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || work(shared, handler))
+                .expect("failed to spawn worker");
+        }
+        Pool {
+            shared,
+            max_connections,
+            queue_timeout,
+        }
+    }
+
+    /// Queues a connection, or hands it back when the pool is full.
+    pub fn submit(&self, conn: T) -> Result<(), T> {
+        let admitted = self
+            .shared
+            .active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max_connections {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !admitted {
+            return Err(conn);
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.waiting.push_back((Instant::now(), conn));
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Takes back the connections that have waited `queue_timeout` for a
+    /// worker, so that they can be refused rather than left hanging.
+    pub fn expired(&self) -> Vec<T> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let mut expired = Vec::new();
+        while matches!(queue.waiting.front(), Some((queued, _)) if queued.elapsed() >= self.queue_timeout)
+        {
+            if let Some((_, conn)) = queue.waiting.pop_front() {
+                self.shared.active.fetch_sub(1, Ordering::SeqCst);
+                expired.push(conn);
+            }
+        }
+        expired
+    }
+
+    /// Number of connections being served or waiting for a worker.
+    pub fn active(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    /// Lets the workers leave once the queue is empty.
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+    }
+}
+
+fn work<T, F: Fn(T)>(shared: Arc<Shared<T>>, handler: Arc<F>) {
+    loop {
+        let conn = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some((_, conn)) = queue.waiting.pop_front() {
+                    break conn;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+        // A panicking connection must not take the worker down with it.
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(conn))).is_err() {
+            error!("connection handler panicked");
+        }
+        shared.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_pool_limit() {
+    use std::sync::mpsc::sync_channel;
+
+    let (release, wait) = sync_channel::<()>(0);
+    let wait = Mutex::new(wait);
+    let pool = Pool::new(1, 2, Duration::from_secs(60), move |_: u32| {
+        wait.lock().unwrap().recv().unwrap();
+    });
+    assert!(pool.submit(1).is_ok());
+    assert!(pool.submit(2).is_ok());
+    assert_eq!(pool.submit(3), Err(3));
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    while pool.active() > 0 {
+        thread::yield_now();
+    }
+    assert!(pool.submit(4).is_ok());
+    release.send(()).unwrap();
+}
+
+#[test]
+fn test_pool_queue_timeout() {
+    use std::sync::mpsc::sync_channel;
+
+    let (release, wait) = sync_channel::<()>(0);
+    let wait = Mutex::new(wait);
+    let pool = Pool::new(1, 3, Duration::from_millis(50), move |_: u32| {
+        wait.lock().unwrap().recv().unwrap();
+    });
+    assert!(pool.submit(1).is_ok());
+    // Wait until the worker holds the first, so the second is queued.
+    while !pool.shared.queue.lock().unwrap().waiting.is_empty() {
+        thread::yield_now();
+    }
+    assert!(pool.submit(2).is_ok());
+    assert!(pool.expired().is_empty());
+    thread::sleep(Duration::from_millis(60));
+    assert!(pool.submit(3).is_ok());
+    // Only the one left in the queue past the timeout, not the one being
+    // served or the one just queued.
+    assert_eq!(pool.expired(), vec![2]);
+    assert_eq!(pool.active(), 2);
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    while pool.active() > 0 {
+        thread::yield_now();
+    }
+}
@@ -1,104 +1,472 @@
-use crate::index::add::add_index;
-use crate::index::create::create_index;
-use crate::index::delete::delete_index;
-use crate::index::search::search_index;
-use crate::CONF;
-use serde::Serialize;
+use crate::auth::{self, ApiKey, Role};
+use crate::stream::Stream;
+use crate::{CONF, ENGINE, STARTED};
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
 use serde_json::Value;
+use tantivy_server::{Result, ServerError, Upload};
+use tantivy_server_protocol::{
+    ByteOrder, Cmd, Compression, Encoding, ErrorReply, Framing, Handshake, Hello, Limits, Message,
+    Payload, Response, ServerStats, StatsQuery, Status, UploadBegin, UploadChunk, GREETING,
+    PROTOCOL_V1, PROTOCOL_V2,
+};
 
-use serde::Deserialize;
-use std::io::{Read, Result, Write};
-use std::net::TcpStream;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::marker::PhantomData;
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
 
-#[derive(Serialize, Debug)]
-pub enum Status {
-    Ok,
-    Wrong,
+/// Held-back replies are flushed once they reach this size, even while the
+/// client keeps sending, so they neither pile up nor wait indefinitely.
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct RequestMessage {
+    /// Optional client-supplied correlation id, echoed back in every reply.
+    #[serde(default)]
+    pub(crate) id: Option<Value>,
+    cmd: Cmd,
+    /// API key for this request alone, overriding the one given in `Hello`.
+    #[serde(default)]
+    token: Option<String>,
+    /// The whole frame, kept so the body can be decoded straight into the
+    /// command's payload type once `cmd` is known.
+    #[serde(skip)]
+    frame: Vec<u8>,
+    /// How `frame` is encoded.
+    #[serde(skip)]
+    encoding: Encoding,
 }
 
-#[derive(Serialize, Debug)]
-pub struct Message {
-    pub(crate) status: Status,
-    pub(crate) message: Option<Value>,
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct RequestBody<T> {
+    body: Body<T>,
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
-enum Cmd {
-    Create,
-    Add,
-    Search,
-    Delete,
+/// A request body, given either as a JSON object or, as older clients send
+/// it, as a string holding one.
+struct Body<T>(T);
+
+/// The fields of a body that decide which grant a command needs.
+#[derive(Deserialize, Debug)]
+struct Target {
+    #[serde(default)]
+    index: String,
+    #[serde(default)]
+    field: String,
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
-struct RequestMessage {
-    cmd: Cmd,
-    body: String,
+/// A client connection. Reads are buffered and replies are held back, so
+/// that a client can pipeline several requests before reading the replies.
+pub struct Connection {
+    stream: BufReader<Stream>,
+    /// Replies not yet written to the stream.
+    pending: Vec<u8>,
+    /// The protocol version negotiated with `Hello`, v1 until then.
+    protocol: u8,
+    /// How frames are laid out: the byte order the client's first frame was
+    /// written in, JSON and no compression until a `Hello` asks otherwise.
+    framing: Framing,
+    /// Whether the client's first frame is still to be read.
+    first_frame: bool,
+    /// The chunked upload in progress, if any.
+    upload: Option<Upload>,
+    /// The API key given in `Hello`.
+    key: Option<&'static ApiKey>,
 }
 
 #[derive(Copy, Clone)]
 pub struct TantivyServer;
 
-impl Message {
-    pub fn encode(self) -> Vec<u8> {
-        let msg = serde_json::to_vec(&self).unwrap();
-        let mut buf = Vec::with_capacity(4 + msg.len());
-        if CONF.byteorder == "big" {
-            buf.extend_from_slice(&(msg.len() as u32).to_be_bytes());
-        } else {
-            buf.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+/// The v2 reply to a request that took since `started`.
+pub fn envelope(id: Option<Value>, started: Instant, result: Result<Payload>) -> Response {
+    let took_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(payload) => Response {
+            id,
+            status: Status::Ok,
+            code: 0,
+            took_ms,
+            payload: Some(payload),
+            error: None,
+        },
+        Err(e) => Response {
+            id,
+            status: Status::Wrong,
+            code: e.code(),
+            took_ms,
+            payload: None,
+            error: Some(ErrorReply::from(&e)),
+        },
+    }
+}
+
+impl RequestMessage {
+    fn body<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(self.encoding.decode::<RequestBody<T>>(&self.frame)?.body.0)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Body<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct BodyVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: DeserializeOwned> Visitor<'de> for BodyVisitor<T> {
+            type Value = Body<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object or a string holding one")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Body<T>, E> {
+                serde_json::from_str(v).map(Body).map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<Body<T>, A::Error> {
+                T::deserialize(MapAccessDeserializer::new(map)).map(Body)
+            }
         }
-        // if cfg!(target_endian = "big") {
-        // } else {
-        // }
-        buf.extend_from_slice(&msg);
-        buf
+
+        deserializer.deserialize_any(BodyVisitor(PhantomData))
+    }
+}
+
+/// The server default from `app.yml`.
+pub fn configured_byteorder() -> ByteOrder {
+    if CONF.byteorder == "big" {
+        ByteOrder::Big
+    } else {
+        ByteOrder::Little
+    }
+}
+
+fn handshake(conn: &Connection) -> Handshake {
+    Handshake {
+        greeting: GREETING.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocols: vec![PROTOCOL_V1, PROTOCOL_V2],
+        encodings: vec![Encoding::Json, Encoding::Msgpack, Encoding::Cbor],
+        compressions: vec![Compression::None, Compression::Lz4, Compression::Zstd],
+        protocol: conn.protocol,
+        byteorder: conn.framing.byteorder,
+        encoding: conn.framing.encoding,
+        compression: conn.framing.compression,
+        auth: CONF.auth.is_some(),
+        limits: Limits {
+            max_frame_bytes: CONF.max_frame_bytes as u64,
+            max_page_size: CONF.index.max_page_size,
+            compress_threshold_bytes: CONF.compression.threshold_bytes,
+        },
+    }
+}
+
+impl Connection {
+    pub fn new(stream: Stream) -> Connection {
+        Connection {
+            stream: BufReader::new(stream),
+            pending: Vec::new(),
+            protocol: PROTOCOL_V1,
+            framing: Framing {
+                compression_conf: CONF.compression,
+                ..Framing::new(configured_byteorder())
+            },
+            first_frame: true,
+            upload: None,
+            key: None,
+        }
+    }
+
+    /// Closes the connection after flushing any pending replies. Unread
+    /// input is drained for a moment first, so the peer gets the replies
+    /// rather than a reset.
+    pub fn close(&mut self) -> Result<()> {
+        self.flush()?;
+        self.stream.get_mut().shutdown(Shutdown::Write)?;
+        self.stream
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(1)))?;
+        let _ = io::copy(
+            &mut (&mut self.stream).take(CONF.max_frame_bytes as u64),
+            &mut io::sink(),
+        );
+        Ok(())
+    }
+
+    pub fn shutdown(&mut self) -> Result<()> {
+        Ok(self.stream.get_mut().shutdown(Shutdown::Both)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(&self.pending)?;
+        stream.flush()?;
+        self.pending.clear();
+        Ok(())
     }
 }
 
 impl TantivyServer {
-    pub fn send(self, stream: &mut TcpStream, msg: Message) -> Result<()> {
-        let data = &msg.encode();
-        stream.write(&data).expect("write_all while receive error");
-        stream.flush().expect("fail to flush");
+    pub fn send<T: Serialize>(self, conn: &mut Connection, msg: &T) -> Result<()> {
+        let frame = conn.framing.encode(msg);
+        conn.pending.extend_from_slice(&frame);
+        // Replies to pipelined requests are held back until every frame the
+        // client already sent has been answered, then flushed together.
+        if conn.stream.buffer().is_empty() || conn.pending.len() >= MAX_PENDING_BYTES {
+            conn.flush()?;
+        }
         Ok(())
     }
 
-    pub fn receive(self, stream: &mut TcpStream) -> Result<()> {
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf)?;
-        // stream.read_exact(&mut buf)?;
-        let len: u32;
-        if CONF.byteorder == "big" {
-            len = u32::from_be_bytes(buf);
+    /// Sends the unsolicited handshake frame, in the v1 `Message` shape since
+    /// nothing has been negotiated yet, and framed so that a client can tell
+    /// its byte order.
+    pub fn greet(self, conn: &mut Connection) -> Result<()> {
+        let msg = Message {
+            id: None,
+            status: Status::Ok,
+            code: None,
+            message: Some(serde_json::to_value(handshake(conn)).unwrap()),
+        };
+        let frame = conn.framing.encode_first(&msg);
+        conn.pending.extend_from_slice(&frame);
+        conn.flush()
+    }
+
+    pub fn receive(self, conn: &mut Connection) -> Result<RequestMessage> {
+        let buf = if conn.first_frame {
+            conn.first_frame = false;
+            conn.framing
+                .read_first(&mut conn.stream, CONF.max_frame_bytes)?
         } else {
-            len = u32::from_le_bytes(buf);
-        }
-        let mut buf: Vec<u8> = vec![0u8; len as usize];
-        stream.read_exact(&mut buf)?;
+            conn.framing.read(&mut conn.stream, CONF.max_frame_bytes)?
+        };
+        let mut msg = conn.framing.decode::<RequestMessage>(&buf)?;
+        msg.frame = buf;
+        msg.encoding = conn.framing.encoding;
+        Ok(msg)
+    }
 
-        let msg = serde_json::from_slice::<RequestMessage>(&buf)?;
-        match msg.cmd {
+    pub fn handle(self, conn: &mut Connection, msg: RequestMessage) -> Result<Payload> {
+        let key = match msg.token {
+            Some(ref token) => auth::authenticate(token)?,
+            None => conn.key,
+        };
+        if let Some(role) = required_role(&msg.cmd) {
+            let target = msg.body::<Target>()?;
+            // An empty field deletes every document.
+            let role = if msg.cmd == Cmd::Delete && target.field.is_empty() {
+                Role::Admin
+            } else {
+                role
+            };
+            auth::authorize(key, role, &target.index)?;
+        }
+        let payload = match msg.cmd {
+            Cmd::Hello => {
+                let hello = msg.body::<Hello>()?;
+                // Checked before anything changes, so that a refused Hello
+                // leaves the connection, and the framing of its error reply,
+                // as they were.
+                if let Some(protocol) = hello.protocol {
+                    if protocol != PROTOCOL_V1 && protocol != PROTOCOL_V2 {
+                        return Err(ServerError::BadRequest(format!(
+                            "unsupported protocol version {}",
+                            protocol
+                        )));
+                    }
+                }
+                let key = match hello.token {
+                    Some(ref token) => auth::authenticate(token)?,
+                    None => conn.key,
+                };
+                conn.key = key;
+                conn.protocol = hello.protocol.unwrap_or(conn.protocol);
+                conn.framing.byteorder = hello.byteorder.unwrap_or(conn.framing.byteorder);
+                conn.framing.encoding = hello.encoding.unwrap_or(conn.framing.encoding);
+                conn.framing.compression = hello.compression.unwrap_or(conn.framing.compression);
+                Payload::Hello(handshake(conn))
+            }
             Cmd::Create => {
-                create_index(&msg.body)?;
+                ENGINE.create(msg.body()?)?;
+                Payload::Created {}
+            }
+            Cmd::Add => Payload::Added(ENGINE.add(msg.body()?)?),
+            Cmd::Delete => Payload::Deleted(ENGINE.delete(msg.body()?)?),
+            Cmd::Search => Payload::Search(ENGINE.search(msg.body()?)?),
+            Cmd::Describe => Payload::Describe(ENGINE.describe(msg.body()?)?),
+            Cmd::UploadBegin => {
+                let begin = msg.body::<UploadBegin>()?;
+                // Starting over discards any unfinished upload.
+                conn.upload = None;
+                conn.upload = Some(ENGINE.begin_upload(begin.index)?);
+                Payload::Upload { docs: 0 }
             }
-            Cmd::Add => {
-                add_index(&msg.body)?;
+            Cmd::UploadChunk => {
+                let chunk = msg.body::<UploadChunk>()?;
+                let upload = conn.upload.as_mut().ok_or_else(no_upload)?;
+                match upload.add(chunk.data) {
+                    Ok(docs) => Payload::Upload { docs },
+                    Err(e) => {
+                        conn.upload = None;
+                        return Err(e);
+                    }
+                }
             }
-            Cmd::Delete => {
-                delete_index(&msg.body)?;
+            Cmd::UploadEnd => {
+                let upload = conn.upload.take().ok_or_else(no_upload)?;
+                Payload::Added(upload.commit()?)
             }
-            Cmd::Search => {
-                let res = search_index(&msg.body)?;
+            Cmd::Stats => {
+                let query = msg.body::<StatsQuery>()?;
+                // Lists only the indices the key may read.
+                let indices = ENGINE.stats(|index| {
+                    query.index.as_deref().is_none_or(|only| only == index)
+                        && auth::authorize(key, Role::Read, index).is_ok()
+                })?;
+                Payload::Stats(ServerStats {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_secs: STARTED.elapsed().as_secs(),
+                    indices,
+                })
+            }
+            Cmd::Commit => Payload::Committed {
+                opstamp: ENGINE.commit(msg.body()?)?,
+            },
+            Cmd::Merge => Payload::Merged(ENGINE.merge(msg.body()?)?),
+        };
+        Ok(payload)
+    }
+
+    /// Answers a request in the connection's protocol: one `Response` in v2,
+    /// the original `Message` frames in v1.
+    pub fn reply(
+        self,
+        conn: &mut Connection,
+        id: Option<Value>,
+        started: Instant,
+        result: Result<Payload>,
+    ) -> Result<()> {
+        if conn.protocol == PROTOCOL_V2 {
+            let response = envelope(id, started, result);
+            return self.send(conn, &response);
+        }
+
+        match result {
+            Ok(Payload::Search(res)) => {
                 self.send(
-                    stream,
-                    Message {
+                    conn,
+                    &Message {
+                        id: id.clone(),
                         status: Status::Ok,
-                        message: Some(serde_json::to_value(res).unwrap()),
+                        code: None,
+                        message: Some(serde_json::to_value(res.into_v1()).unwrap()),
                     },
                 )?;
+                self.send(
+                    conn,
+                    &Message {
+                        id,
+                        status: Status::Ok,
+                        code: None,
+                        message: None,
+                    },
+                )
             }
+            Ok(Payload::Hello(handshake)) => self.send(
+                conn,
+                &Message {
+                    id,
+                    status: Status::Ok,
+                    code: None,
+                    message: Some(serde_json::to_value(handshake).unwrap()),
+                },
+            ),
+            Ok(_) => self.send(
+                conn,
+                &Message {
+                    id,
+                    status: Status::Ok,
+                    code: None,
+                    message: None,
+                },
+            ),
+            Err(e) => self.send(
+                conn,
+                &Message {
+                    id,
+                    status: Status::Wrong,
+                    code: Some(e.code()),
+                    message: Some(serde_json::to_value(e.to_string()).unwrap()),
+                },
+            ),
         }
-        Ok(())
+    }
+}
+
+/// The grant a command needs on its index. Upload chunks ride on the
+/// `UploadBegin` that was already authorized, and `Stats` leaves out the
+/// indices the key cannot read.
+fn required_role(cmd: &Cmd) -> Option<Role> {
+    match cmd {
+        Cmd::Hello | Cmd::UploadChunk | Cmd::UploadEnd | Cmd::Stats => None,
+        Cmd::Search | Cmd::Describe => Some(Role::Read),
+        Cmd::Add | Cmd::Delete | Cmd::UploadBegin | Cmd::Commit => Some(Role::Write),
+        Cmd::Create | Cmd::Merge => Some(Role::Admin),
+    }
+}
+
+fn no_upload() -> ServerError {
+    ServerError::BadRequest("no upload in progress, send UploadBegin first".to_string())
+}
+
+#[test]
+fn test_request_body() {
+    let nested = br#"{"id":1,"cmd":"Hello","body":{"protocol":2}}"#;
+    let quoted = br#"{"cmd":"Hello","body":"{\"protocol\":2}"}"#;
+    for frame in [&nested[..], &quoted[..]].iter() {
+        let mut msg = serde_json::from_slice::<RequestMessage>(frame).unwrap();
+        msg.frame = frame.to_vec();
+        assert_eq!(msg.cmd, Cmd::Hello);
+        assert_eq!(msg.body::<Hello>().unwrap().protocol, Some(2));
+    }
+}
+
+#[test]
+fn test_binary_encodings() {
+    use std::collections::BTreeMap;
+    use tantivy_server_protocol::DocValue;
+
+    let object = |entries: Vec<(&str, DocValue)>| {
+        DocValue::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    };
+    let blob = DocValue::Bytes(vec![0, 159, 255]);
+    let request = object(vec![
+        ("cmd", DocValue::from("UploadChunk".to_string())),
+        (
+            "body",
+            object(vec![(
+                "data",
+                DocValue::Array(vec![object(vec![("blob", blob.clone())])]),
+            )]),
+        ),
+    ]);
+    for encoding in [Encoding::Msgpack, Encoding::Cbor].iter() {
+        let frame = encoding.to_vec(&request);
+        let mut msg = encoding.decode::<RequestMessage>(&frame).unwrap();
+        msg.frame = frame;
+        msg.encoding = *encoding;
+        assert_eq!(msg.cmd, Cmd::UploadChunk);
+        assert_eq!(msg.body::<UploadChunk>().unwrap().data[0]["blob"], blob);
     }
 }
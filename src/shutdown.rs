@@ -0,0 +1,74 @@
+use crate::stream::Stream;
+use lazy_static::lazy_static;
+use log::info;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown as Side;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    pub static ref SHUTDOWN: Shutdown = Shutdown {
+        requested: Arc::new(AtomicBool::new(false)),
+        streams: Mutex::new(HashMap::new()),
+        next_id: AtomicUsize::new(0),
+    };
+}
+
+/// Tracks a SIGTERM/SIGINT and the open connections to wake when one
+/// arrives.
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    streams: Mutex<HashMap<usize, Stream>>,
+    next_id: AtomicUsize,
+}
+
+/// Keeps a connection registered until dropped.
+pub struct Tracked {
+    id: usize,
+}
+
+impl Shutdown {
+    /// Installs the signal handlers. A second signal while draining exits
+    /// at once.
+    pub fn install(&self) -> io::Result<()> {
+        for &signal in &[SIGTERM, SIGINT] {
+            flag::register_conditional_shutdown(signal, 1, self.requested.clone())?;
+            flag::register(signal, self.requested.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Registers a connection so `wake_idle` can reach it.
+    pub fn track(&self, stream: &Stream) -> io::Result<Tracked> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(id, stream.try_clone_socket()?);
+        Ok(Tracked { id })
+    }
+
+    /// Shuts the read side of every open connection. Workers waiting for a
+    /// request see end of stream and leave, while a request being handled
+    /// still gets its reply.
+    pub fn wake_idle(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        info!("closing {} connections for shutdown", streams.len());
+        for stream in streams.values_mut() {
+            let _ = stream.shutdown(Side::Read);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        SHUTDOWN.streams.lock().unwrap().remove(&self.id);
+    }
+}
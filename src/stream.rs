@@ -0,0 +1,260 @@
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use std::{fs, path::PathBuf};
+
+/// A client socket, whichever listener accepted it. Both speak the same
+/// framing.
+pub enum Stream {
+    Tcp(TcpStream),
+    /// The handshake runs on the first read or write, in the worker.
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct UnixSocketConf {
+    pub path: String,
+    /// Permission bits of the socket file, which decide who may connect.
+    #[serde(default = "default_mode")]
+    pub mode: u32,
+}
+
+fn default_mode() -> u32 {
+    0o660
+}
+
+/// A non-blocking listener, polled by the accept loop.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    /// The socket file is removed when the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: &str) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    pub fn bind_tls(addr: &str, config: Arc<ServerConfig>) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tls(listener, config))
+    }
+
+    /// Binds the socket file at `conf.path`, replacing a stale one left by
+    /// an earlier run, and restricts it to `conf.mode`. The socket is bound
+    /// in a directory only this user may enter and moved into place once
+    /// its mode is set, so no one can connect before that.
+    #[cfg(unix)]
+    pub fn bind_unix(conf: &UnixSocketConf) -> io::Result<Listener> {
+        let path = PathBuf::from(&conf.path);
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", conf.path),
+                ));
+            }
+            // Stale only if no server answers on it.
+            if UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a server is already listening on {}", conf.path),
+                ));
+            }
+        }
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+        let private = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let result = bind_private(&private.join(name), &path, conf.mode);
+        let _ = fs::remove_dir(&private);
+        let listener = result?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(listener, path))
+    }
+
+    /// Accepts a connection, in blocking mode.
+    pub fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(l) => Stream::Tcp(l.accept()?.0),
+            Listener::Tls(l, config) => {
+                let (sock, _) = l.accept()?;
+                let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+                Stream::Tls(Box::new(StreamOwned::new(conn, sock)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l, _) => Stream::Unix(l.accept()?.0),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+/// Binds the socket at `bound`, in a private directory, sets its mode and
+/// renames it to `path`, replacing a stale socket there.
+#[cfg(unix)]
+fn bind_private(
+    bound: &std::path::Path,
+    path: &std::path::Path,
+    mode: u32,
+) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(bound)?;
+    let result = fs::set_permissions(bound, fs::Permissions::from_mode(mode))
+        .and_then(|_| fs::rename(bound, path));
+    if result.is_err() {
+        let _ = fs::remove_file(bound);
+    }
+    result.map(|_| listener)
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Stream {
+    /// A second handle on the underlying socket, bypassing TLS, that can
+    /// shut it down from another thread.
+    pub fn try_clone_socket(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            Stream::Tls(s) => s.sock.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// Shuts the socket down, sending a TLS close_notify first when the
+    /// write side closes.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            Stream::Tls(s) => {
+                if how != Shutdown::Read {
+                    s.conn.send_close_notify();
+                    let _ = s.flush();
+                }
+                s.sock.shutdown(how)
+            }
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Tls(s) => s.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            Stream::Tls(s) => s.sock.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            Stream::Tls(s) => s.sock.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bind_unix() {
+    let path = std::env::temp_dir().join(format!("tantivy-server-{}.sock", std::process::id()));
+    let conf = UnixSocketConf {
+        path: path.to_string_lossy().into_owned(),
+        mode: 0o600,
+    };
+    // A socket file left by an earlier run, which no one listens on, is
+    // replaced.
+    drop(UnixListener::bind(&path).unwrap());
+    let listener = Listener::bind_unix(&conf).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut stream = loop {
+        match listener.accept() {
+            Ok(stream) => break stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::yield_now(),
+            Err(e) => panic!("{}", e),
+        }
+    };
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // One a server is still listening on is not.
+    let e = Listener::bind_unix(&conf).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    drop(listener);
+    assert!(!path.exists());
+}
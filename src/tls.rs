@@ -0,0 +1,188 @@
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind};
+use std::sync::Arc;
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TlsConf {
+    /// PEM certificate chain, leaf first.
+    pub cert: String,
+    /// PEM private key, PKCS#8, RSA or SEC1.
+    pub key: String,
+    /// PEM bundle of CAs that client certificates must chain to. When set,
+    /// clients without a valid certificate are refused.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+impl TlsConf {
+    pub fn server_config(&self) -> io::Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca {
+            Some(ref path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(&cert).map_err(|e| invalid(path, e))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| invalid(&self.cert, e))?;
+        Ok(Arc::new(config))
+    }
+
+    /// The certificate and key in the form `tiny_http` takes them.
+    pub fn pem(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((fs::read(&self.cert)?, fs::read(&self.key)?))
+    }
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificate found"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(invalid(path, "no private key found")),
+        }
+    }
+}
+
+fn invalid<E: std::fmt::Display>(path: &str, e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e))
+}
+
+/// A CA, and a server certificate for `localhost` and a client one it
+/// signed, for the TLS tests.
+#[cfg(test)]
+pub struct TestCerts {
+    /// The server's side, as files under the directory given.
+    pub conf: TlsConf,
+    ca: rcgen::Certificate,
+    client: rcgen::Certificate,
+}
+
+#[cfg(test)]
+impl TestCerts {
+    pub fn new(dir: &std::path::Path) -> TestCerts {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa};
+
+        fs::create_dir_all(dir).unwrap();
+        let write = |name: &str, pem: String| {
+            let path = dir.join(name);
+            fs::write(&path, pem).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let client = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let conf = TlsConf {
+            cert: write("server.pem", server.serialize_pem_with_signer(&ca).unwrap()),
+            key: write("server.key", server.serialize_private_key_pem()),
+            client_ca: Some(write("ca.pem", ca.serialize_pem().unwrap())),
+        };
+        TestCerts { conf, ca, client }
+    }
+
+    /// A TLS connection to `addr`, presenting the client certificate when
+    /// `with_cert` is set. The handshake happens on the first read or write.
+    pub fn connect(
+        &self,
+        addr: std::net::SocketAddr,
+        with_cert: bool,
+    ) -> io::Result<rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream>> {
+        use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+        use std::convert::TryFrom;
+        use std::net::TcpStream;
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(self.ca.serialize_der().unwrap()))
+            .unwrap();
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if with_cert {
+            let cert = self.client.serialize_der_with_signer(&self.ca).unwrap();
+            let key = PrivateKey(self.client.serialize_private_key_der());
+            builder
+                .with_single_cert(vec![Certificate(cert)], key)
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        Ok(StreamOwned::new(conn, TcpStream::connect(addr)?))
+    }
+}
+
+#[test]
+fn test_mutual_tls() {
+    use crate::stream::{Listener, Stream};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let dir = std::env::temp_dir().join(format!("tantivy-server-tls-{}", std::process::id()));
+    let certs = TestCerts::new(&dir);
+
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    tcp.set_nonblocking(true).unwrap();
+    let listener = Listener::Tls(tcp, certs.conf.server_config().unwrap());
+
+    let connect = |with_cert: bool| {
+        let mut stream = certs.connect(addr, with_cert).unwrap();
+        thread::spawn(move || {
+            stream.write_all(b"ping")?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf)?;
+            Ok::<_, io::Error>(buf)
+        })
+    };
+    let accept = || -> Stream {
+        loop {
+            match listener.accept() {
+                Ok(stream) => return stream,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    };
+
+    let client_thread = connect(true);
+    let mut stream = accept();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    stream.write_all(b"pong").unwrap();
+    assert_eq!(&client_thread.join().unwrap().unwrap(), b"pong");
+
+    // Without a client certificate the handshake fails on the server side.
+    let client_thread = connect(false);
+    let mut stream = accept();
+    assert!(stream.read_exact(&mut buf).is_err());
+    drop(stream);
+    assert!(client_thread.join().unwrap().is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}